@@ -0,0 +1,82 @@
+//! Packets per second through `Peer::encapsulate` and the endpoint lookup of the send path, with
+//! several threads sending to the same connected peer at once.
+//!
+//!     cargo bench --bench peer_fast_path
+use std::hint::black_box;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wontun::{Action, HandshakeResponse, Packet, Peer, PeerName};
+
+const PACKET_SIZE: usize = 1400;
+const BUF_SIZE: usize = 1504;
+
+fn connected_peer() -> Peer {
+    let peer = Peer::new();
+    peer.set_endpoint(SocketAddrV4::new([127, 0, 0, 1].into(), 19988));
+
+    let name = PeerName::new("bench").unwrap();
+    let mut buf = [0u8; BUF_SIZE];
+    let Action::WriteToNetwork(_) = peer.send_handshake(name.as_ref(), &mut buf) else {
+        panic!("no handshake sent");
+    };
+    let response = Packet::HandshakeResponse(HandshakeResponse {
+        assigned_idx: 1,
+        sender_idx: 0,
+    });
+    let Action::WriteToNetwork(_) = peer.handle_incoming_packet(response, &mut buf) else {
+        panic!("not connected");
+    };
+
+    peer
+}
+
+fn send_from_threads(peer: &Arc<Peer>, threads: usize, iters: u64) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let peer = Arc::clone(peer);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                let packet = [0xabu8; PACKET_SIZE];
+                let mut buf = [0u8; BUF_SIZE];
+                barrier.wait();
+                for _ in 0..iters {
+                    if let Action::WriteToNetwork(data) = peer.encapsulate(&packet, &mut buf) {
+                        black_box(data);
+                    }
+                    black_box(peer.endpoint().addr);
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_fast_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("peer_fast_path");
+    let peer = Arc::new(connected_peer());
+
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| b.iter_custom(|iters| send_from_threads(&peer, threads, iters)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_fast_path);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+//! Packets per second over loopback, one syscall per datagram vs recvmmsg/sendmmsg batches.
+//!
+//!     cargo bench --bench udp_batch
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use wontun::{Dest, RecvBatch, SendBatch, BATCH_SIZE};
+
+const PACKET_SIZE: usize = 1400;
+const BUF_SIZE: usize = 1504;
+
+fn socket_pair() -> (UdpSocket, UdpSocket, SocketAddrV4) {
+    let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    rx.set_nonblocking(true).unwrap();
+    let SocketAddr::V4(addr) = rx.local_addr().unwrap() else {
+        unreachable!()
+    };
+
+    (rx, tx, addr)
+}
+
+fn bench_udp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_loopback");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+    let packet = [0xabu8; PACKET_SIZE];
+
+    group.bench_function("send_to/recv_from", |b| {
+        let (rx, tx, addr) = socket_pair();
+        let mut buf = [0u8; BUF_SIZE];
+        b.iter(|| {
+            for _ in 0..BATCH_SIZE {
+                tx.send_to(&packet, addr).unwrap();
+            }
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                if rx.recv_from(&mut buf).is_ok() {
+                    received += 1;
+                }
+            }
+        });
+    });
+
+    group.bench_function("sendmmsg/recvmmsg", |b| {
+        let (rx, tx, addr) = socket_pair();
+        let mut send = SendBatch::default();
+        let mut recv = RecvBatch::new(BUF_SIZE);
+        b.iter(|| {
+            for _ in 0..BATCH_SIZE {
+                send.push(&packet, Dest::Addr(addr));
+            }
+            send.flush(&tx).unwrap();
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                received += recv.recv(&rx).unwrap_or(0);
+            }
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_udp);
+criterion_main!(benches);
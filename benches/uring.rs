@@ -0,0 +1,77 @@
+//! Packets per second over loopback, recvmmsg/sendmmsg batches vs io_uring sends and receives
+//! of registered buffers, both with a batch in flight per iteration.
+//!
+//!     cargo bench --features io-uring --bench uring
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use wontun::{Completion, Dest, RecvBatch, Ring, SendBatch, BATCH_SIZE};
+
+const PACKET_SIZE: usize = 1400;
+const BUF_SIZE: usize = 1504;
+
+fn socket_pair() -> (UdpSocket, UdpSocket, SocketAddrV4) {
+    let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    rx.set_nonblocking(true).unwrap();
+    let SocketAddr::V4(addr) = rx.local_addr().unwrap() else {
+        unreachable!()
+    };
+
+    (rx, tx, addr)
+}
+
+fn bench_backends(c: &mut Criterion) {
+    let mut group = c.benchmark_group("backend_loopback");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+    let packet = [0xabu8; PACKET_SIZE];
+
+    group.bench_function("epoll/mmsg", |b| {
+        let (rx, tx, addr) = socket_pair();
+        let mut send = SendBatch::default();
+        let mut recv = RecvBatch::new(BUF_SIZE);
+        b.iter(|| {
+            for _ in 0..BATCH_SIZE {
+                send.push(&packet, Dest::Addr(addr));
+            }
+            send.flush(&tx).unwrap();
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                received += recv.recv(&rx).unwrap_or(0);
+            }
+        });
+    });
+
+    group.bench_function("io_uring", |b| {
+        let (rx, tx, addr) = socket_pair();
+        // blocking, so that receives wait in the kernel instead of failing with EAGAIN
+        rx.set_nonblocking(false).unwrap();
+        let mut ring = Ring::new(2 * BATCH_SIZE, BUF_SIZE, BUF_SIZE).unwrap();
+        for slot in 0..BATCH_SIZE {
+            ring.buf(slot)[..PACKET_SIZE].copy_from_slice(&packet);
+        }
+        b.iter(|| {
+            for slot in 0..BATCH_SIZE {
+                let recv = ring.recv(&rx, BATCH_SIZE + slot);
+                ring.push(&[recv]).unwrap();
+                let send = ring.send(&tx, slot, 0..PACKET_SIZE, Dest::Addr(addr));
+                ring.push(&[send]).unwrap();
+            }
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                ring.wait().unwrap();
+                while let Some(completion) = ring.completion() {
+                    if let Completion::Recv { res: Ok(_), .. } = completion {
+                        received += 1;
+                    }
+                }
+            }
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_backends);
+criterion_main!(benches);
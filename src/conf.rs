@@ -1,10 +1,20 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use ip_network::IpNetworkParseError;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+mod check;
+
+pub use check::{Diagnostic, Severity};
+
 #[derive(Error, Debug)]
 pub enum ConfError {
     #[error("invalid ini: {0}")]
@@ -21,35 +31,240 @@ pub enum ConfError {
 
     #[error("missing interface definition")]
     MissingInterface,
+
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+
+    #[error("{0} is included more than once")]
+    DuplicateInclude(PathBuf),
+
+    #[error("Include needs the path of the conf file to resolve against")]
+    IncludeWithoutPath,
+
+    #[error("{0}: {1}")]
+    InFile(PathBuf, #[source] Box<ConfError>),
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Conf {
     pub interface: InterfaceConf,
     pub peers: Vec<PeerConf>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InterfaceConf {
     pub name: String,
     pub address: (Ipv4Addr, u8),
+    #[serde(default = "default_listen_port")]
     pub listen_port: u16,
+    /// Name of the tun interface, `%d` patterns are completed by the kernel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tun_name: Option<String>,
+    /// MTU of the tun interface, `Conf::DEFAULT_MTU` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PeerConf {
     pub name: String,
     pub endpoint: Option<SocketAddrV4>,
+    #[serde(default)]
     pub allowed_ips: Vec<(Ipv4Addr, u8)>,
 }
 
+fn default_listen_port() -> u16 {
+    Conf::DEFAULT_LISTEN_PORT
+}
+
+/// An ini file that went into a `Conf`, kept around so diagnostics can point back into it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfSource {
+    pub path: Option<PathBuf>,
+    pub text: String,
+}
+
 impl Conf {
     pub const DEFAULT_LISTEN_PORT: u16 = 19988;
+    /// Leaves room for the outer IP and UDP headers and the wontun header on a 1500 byte link
+    pub const DEFAULT_MTU: u16 = 1400;
 
+    /// Parses a conf from ini text. It has no path to resolve `Include`s against, those fail
+    /// with [`ConfError::IncludeWithoutPath`], see [`Conf::parse_from_with_base`].
     pub fn parse_from(source: &str) -> Result<Self, ConfError> {
-        let sections: Vec<Section> = serde_ini::from_str(source)?;
+        let main = ConfSource {
+            path: None,
+            text: source.to_string(),
+        };
+        let (conf, _) = Self::merge(main, None, None)?;
+
+        Ok(conf)
+    }
+
+    /// Like [`Conf::parse_from`], but `Include`s are resolved against `base_dir`
+    pub fn parse_from_with_base(source: &str, base_dir: &Path) -> Result<Self, ConfError> {
+        let main = ConfSource {
+            path: None,
+            text: source.to_string(),
+        };
+        let (conf, _) = Self::merge(main, Some(base_dir), None)?;
+
+        Ok(conf)
+    }
 
-        let mut interface = None;
+    /// Reads the conf file at `path` and merges in `[Peer]` fragments from its `Include`s and
+    /// from the `<path>.d/` directory (if present), in that order.
+    ///
+    /// Returns all files that were read in merge order, for use with [`Conf::validate`].
+    pub fn load(path: &Path) -> Result<(Self, Vec<ConfSource>), ConfError> {
+        let main = ConfSource::read(path)?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+
+        Self::merge(main, Some(base_dir), Some(Self::drop_in_dir(path)))
+    }
+
+    /// `<path>.d/`, the directory of `[Peer]` fragments merged into the conf file at `path`
+    pub fn drop_in_dir(path: &Path) -> PathBuf {
+        let mut drop_in = path.as_os_str().to_owned();
+        drop_in.push(".d");
+        PathBuf::from(drop_in)
+    }
+
+    fn merge(
+        main: ConfSource,
+        base_dir: Option<&Path>,
+        drop_in: Option<PathBuf>,
+    ) -> Result<(Self, Vec<ConfSource>), ConfError> {
+        let (mut interfaces, mut peers) = main.parse_sections()?;
+        if interfaces.len() > 1 {
+            return Err(main.error(ConfError::ExtraInterface));
+        }
+        let Some((interface, include)) = interfaces.pop() else {
+            return Err(main.error(ConfError::MissingInterface));
+        };
+
+        let mut fragments = vec![];
+        for path in include.as_deref().unwrap_or("").split(',') {
+            let path = path.trim();
+            if path.is_empty() {
+                continue;
+            }
+            let Some(base_dir) = base_dir else {
+                return Err(main.error(ConfError::IncludeWithoutPath));
+            };
+            fragments.extend(fragment_paths(&base_dir.join(path))?);
+        }
+        if let Some(drop_in) = drop_in.filter(|dir| dir.is_dir()) {
+            fragments.extend(fragment_paths(&drop_in)?);
+        }
+
+        let mut seen = HashSet::new();
+        if let Some(ref path) = main.path {
+            seen.insert(canonical_path(path)?);
+        }
+        let mut sources = vec![main];
+
+        for path in fragments {
+            if !seen.insert(canonical_path(&path)?) {
+                return Err(ConfError::DuplicateInclude(path));
+            }
+            let fragment = ConfSource::read(&path)?;
+            let (interfaces, fragment_peers) = fragment.parse_sections()?;
+            if !interfaces.is_empty() {
+                return Err(fragment.error(ConfError::ExtraInterface));
+            }
+            peers.extend(fragment_peers);
+            sources.push(fragment);
+        }
+
+        Ok((Conf { interface, peers }, sources))
+    }
+
+    /// Canonical ini representation, `parse_from` on the output yields back an equal `Conf`
+    pub fn to_ini(&self) -> String {
+        let mut out = String::new();
+        self.write_ini(&mut out)
+            .expect("writing to a String never fails");
+        out
+    }
+
+    fn write_ini(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let (ip, cidr) = self.interface.address;
+        writeln!(out, "[Interface]")?;
+        writeln!(out, "Name={}", self.interface.name)?;
+        writeln!(out, "Address={ip}/{cidr}")?;
+        writeln!(out, "ListenPort={}", self.interface.listen_port)?;
+        if let Some(ref tun_name) = self.interface.tun_name {
+            writeln!(out, "TunName={tun_name}")?;
+        }
+        if let Some(mtu) = self.interface.mtu {
+            writeln!(out, "Mtu={mtu}")?;
+        }
+
+        for peer in &self.peers {
+            writeln!(out)?;
+            writeln!(out, "[Peer]")?;
+            writeln!(out, "Name={}", peer.name)?;
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(out, "Endpoint={endpoint}")?;
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips: Vec<_> = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|(ip, cidr)| format!("{ip}/{cidr}"))
+                    .collect();
+                writeln!(out, "AllowedIPs={}", allowed_ips.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PeerConf {
+    fn parse(
+        name: String,
+        endpoint: Option<String>,
+        allowed_ips: Option<String>,
+    ) -> Result<Self, ConfError> {
+        let allowed_ips: Result<Vec<_>, _> = allowed_ips
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|allowed_ip| Some(allowed_ip.trim()).filter(|s| !s.is_empty()))
+            .map(|allowed_ip| -> Result<_, IpNetworkParseError> {
+                let ipn = ip_network::Ipv4Network::from_str_truncate(allowed_ip)?;
+                Ok((ipn.network_address(), ipn.netmask()))
+            })
+            .collect();
+        let endpoint = endpoint.and_then(|ep| SocketAddrV4::from_str(&ep).ok());
+
+        Ok(PeerConf {
+            name,
+            allowed_ips: allowed_ips?,
+            endpoint,
+        })
+    }
+}
+
+type InterfaceSection = (InterfaceConf, Option<String>);
+
+impl ConfSource {
+    fn read(path: &Path) -> Result<Self, ConfError> {
+        let text = fs::read_to_string(path).map_err(|err| ConfError::Read(path.to_owned(), err))?;
+        Ok(Self {
+            path: Some(path.to_owned()),
+            text,
+        })
+    }
+
+    /// `[Interface]` sections (along with their `Include` value) and `[Peer]` sections of this file
+    fn parse_sections(&self) -> Result<(Vec<InterfaceSection>, Vec<PeerConf>), ConfError> {
+        let sections: Vec<Section> =
+            serde_ini::from_str(&self.text).map_err(|err| self.error(err))?;
+
+        let mut interfaces = vec![];
         let mut peers = vec![];
 
         for section in sections.into_iter() {
@@ -59,67 +274,84 @@ impl Conf {
                     Endpoint,
                     AllowedIPs,
                 } => {
-                    let allowed_ips: Result<Vec<_>, _> = AllowedIPs
-                        .as_deref()
-                        .unwrap_or("")
-                        .split(',')
-                        .filter_map(|allowed_ip| Some(allowed_ip.trim()).filter(|s| !s.is_empty()))
-                        .map(|allowed_ip| -> Result<_, IpNetworkParseError> {
-                            let ipn = ip_network::Ipv4Network::from_str_truncate(allowed_ip)?;
-                            Ok((ipn.network_address(), ipn.netmask()))
-                        })
-                        .collect();
-                    let endpoint = Endpoint.and_then(|ep| SocketAddrV4::from_str(&ep).ok());
-                    let peer = PeerConf {
-                        name: Name,
-                        allowed_ips: allowed_ips?,
-                        endpoint,
-                    };
+                    let peer = PeerConf::parse(Name, Endpoint, AllowedIPs)
+                        .map_err(|err| self.error(err))?;
                     peers.push(peer);
                 }
                 Section::Interface {
                     Name,
                     Address,
                     ListenPort,
+                    TunName,
+                    Mtu,
+                    Include,
                 } => {
-                    if interface.is_none() {
-                        let address = parse_cidr(Address.trim())?;
-                        interface = Some(InterfaceConf {
-                            name: Name,
-                            address,
-                            listen_port: ListenPort.unwrap_or(Self::DEFAULT_LISTEN_PORT),
-                        });
-                    } else {
-                        return Err(ConfError::ExtraInterface);
-                    }
+                    let address = parse_cidr(Address.trim()).map_err(|err| self.error(err))?;
+                    let interface = InterfaceConf {
+                        name: Name,
+                        address,
+                        listen_port: ListenPort.unwrap_or(Conf::DEFAULT_LISTEN_PORT),
+                        tun_name: TunName,
+                        mtu: Mtu,
+                    };
+                    interfaces.push((interface, Include));
                 }
             }
         }
-        if let Some(interface) = interface {
-            Ok(Conf { interface, peers })
-        } else {
-            Err(ConfError::MissingInterface)
+
+        Ok((interfaces, peers))
+    }
+
+    /// Attributes `err` to this file
+    fn error(&self, err: impl Into<ConfError>) -> ConfError {
+        match self.path {
+            Some(ref path) => ConfError::InFile(path.clone(), Box::new(err.into())),
+            None => err.into(),
         }
     }
 }
 
+/// `path` itself if it is a file, or the `*.conf` files inside it sorted by name
+fn fragment_paths(path: &Path) -> Result<Vec<PathBuf>, ConfError> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let read_err = |err| ConfError::Read(path.to_owned(), err);
+    let mut paths = vec![];
+    for entry in fs::read_dir(path).map_err(read_err)? {
+        let entry_path = entry.map_err(read_err)?.path();
+        if entry_path.is_file() && entry_path.extension() == Some(OsStr::new("conf")) {
+            paths.push(entry_path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn canonical_path(path: &Path) -> Result<PathBuf, ConfError> {
+    path.canonicalize()
+        .map_err(|err| ConfError::Read(path.to_owned(), err))
+}
+
 fn parse_cidr(cidr: &str) -> Result<(Ipv4Addr, u8), ConfError> {
     let (ip_str, subnet_str) = cidr
         .split_once('/')
-        .ok_or_else(|| ConfError::IpFormat("Invalid CIDR format: {cidr}".to_string()))?;
+        .ok_or_else(|| ConfError::IpFormat(format!("Invalid CIDR format: {cidr}")))?;
 
     let ip = ip_str
         .parse::<Ipv4Addr>()
-        .map_err(|_| ConfError::IpFormat("Invalid IP address: {cidr}".to_string()))?;
+        .map_err(|_| ConfError::IpFormat(format!("Invalid IP address: {cidr}")))?;
 
     let subnet = subnet_str
         .parse::<u8>()
-        .map_err(|_| ConfError::IpFormat("Invalid subnet mask: {cidr}".to_string()))?;
+        .map_err(|_| ConfError::IpFormat(format!("Invalid subnet mask: {cidr}")))?;
 
     if subnet > 32 {
-        return Err(ConfError::IpFormat(
-            "Subnet mask must be in the range 0-32: {cidr}".to_string(),
-        ));
+        return Err(ConfError::IpFormat(format!(
+            "Subnet mask must be in the range 0-32: {cidr}"
+        )));
     }
 
     Ok((ip, subnet))
@@ -132,6 +364,9 @@ pub enum Section {
         Name: String,
         Address: String,
         ListenPort: Option<u16>,
+        TunName: Option<String>,
+        Mtu: Option<u16>,
+        Include: Option<String>,
     },
     Peer {
         Name: String,
@@ -166,7 +401,10 @@ AllowedIPs=192.0.2.1/24
                 Section::Interface {
                     Name: "client".into(),
                     Address: "192.0.2.2/24".into(),
-                    ListenPort: Some(19988)
+                    ListenPort: Some(19988),
+                    TunName: None,
+                    Mtu: None,
+                    Include: None,
                 },
                 Section::Peer {
                     Name: "node1".into(),
@@ -188,7 +426,9 @@ AllowedIPs=192.0.2.1/24
                 interface: InterfaceConf {
                     name: "client".into(),
                     address: (Ipv4Addr::from([192, 0, 2, 2]), 24),
-                    listen_port: 19988
+                    listen_port: 19988,
+                    tun_name: None,
+                    mtu: None,
                 },
                 peers: vec![
                     PeerConf {
@@ -206,4 +446,159 @@ AllowedIPs=192.0.2.1/24
             conf
         );
     }
+
+    #[test]
+    fn test_ini_roundtrip() {
+        let input = r#"
+[Interface]
+Name=client-A
+Address=10.10.0.3/24
+TunName=wontun%d
+
+[Peer]
+Name=server
+Endpoint=172.18.0.2:19988
+AllowedIPs=10.10.0.1/24,192.0.2.0/28
+
+[Peer]
+Name=client-B
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let canonical = conf.to_ini();
+        assert_eq!(
+            canonical,
+            r#"[Interface]
+Name=client-A
+Address=10.10.0.3/24
+ListenPort=19988
+TunName=wontun%d
+
+[Peer]
+Name=server
+Endpoint=172.18.0.2:19988
+AllowedIPs=10.10.0.0/24, 192.0.2.0/28
+
+[Peer]
+Name=client-B
+"#
+        );
+
+        let reparsed = Conf::parse_from(&canonical).unwrap();
+        assert_eq!(conf, reparsed);
+        assert_eq!(canonical, reparsed.to_ini());
+
+        let json = serde_json::to_string(&conf).unwrap();
+        assert_eq!(conf, serde_json::from_str(&json).unwrap());
+
+        let toml = toml::to_string(&conf).unwrap();
+        assert_eq!(conf, toml::from_str(&toml).unwrap());
+    }
+
+    /// A directory removed when the test ends, also when it fails
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_load_fragments() {
+        let tmp = TempDir(std::env::temp_dir().join(format!("wontun-conf-{}", std::process::id())));
+        let dir = &tmp.0;
+        let drop_in = dir.join("hub.conf.d");
+        fs::create_dir_all(&drop_in).unwrap();
+
+        let main = dir.join("hub.conf");
+        fs::write(
+            &main,
+            "[Interface]\nName=hub\nAddress=10.10.0.1/24\nInclude=extra.conf\n\n[Peer]\nName=a\n",
+        )
+        .unwrap();
+        fs::write(dir.join("extra.conf"), "[Peer]\nName=b\n").unwrap();
+        fs::write(drop_in.join("20-d.conf"), "[Peer]\nName=d\n").unwrap();
+        fs::write(
+            drop_in.join("10-c.conf"),
+            "[Peer]\nName=c\n\n[Peer]\nName=a\n",
+        )
+        .unwrap();
+        fs::write(drop_in.join("ignored.txt"), "[Peer]\nName=e\n").unwrap();
+
+        let (conf, sources) = Conf::load(&main).unwrap();
+        let names: Vec<_> = conf.peers.iter().map(|peer| peer.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c", "a", "d"]);
+        assert_eq!(sources.len(), 4);
+
+        let diagnostics = conf.validate(&sources);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            format!(
+                "error: {}:5: [Peer] a: duplicate peer name, first defined at {}:7",
+                drop_in.join("10-c.conf").display(),
+                main.display()
+            )
+        );
+
+        fs::write(
+            &main,
+            "[Interface]\nName=hub\nAddress=10.10.0.1/24\nInclude=hub.conf.d\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Conf::load(&main),
+            Err(ConfError::DuplicateInclude(path)) if path == drop_in.join("10-c.conf")
+        ));
+
+        fs::write(
+            drop_in.join("20-d.conf"),
+            "[Interface]\nName=d\nAddress=10.10.0.4/24\n",
+        )
+        .unwrap();
+        fs::write(&main, "[Interface]\nName=hub\nAddress=10.10.0.1/24\n").unwrap();
+        let err = Conf::load(&main).unwrap_err();
+        assert_eq!(
+            Diagnostic::from(err).to_string(),
+            format!(
+                "error: {}: multiple interface definition",
+                drop_in.join("20-d.conf").display()
+            )
+        );
+
+        // without a file there is nothing to resolve against, unless given a directory
+        let text = "[Interface]\nName=hub\nAddress=10.10.0.1/24\nInclude=extra.conf\n";
+        assert!(matches!(
+            Conf::parse_from(text),
+            Err(ConfError::IncludeWithoutPath)
+        ));
+        let conf = Conf::parse_from_with_base(text, dir).unwrap();
+        assert_eq!(conf.peers[0].name, "b");
+    }
+
+    #[test]
+    fn test_diagnostics_in_merge_order() {
+        let tmp =
+            TempDir(std::env::temp_dir().join(format!("wontun-order-{}", std::process::id())));
+        let dir = &tmp.0;
+        fs::create_dir_all(dir).unwrap();
+
+        // sorted by path, the included file would come first
+        let main = dir.join("b.conf");
+        fs::write(
+            &main,
+            "[Interface]\nName=hub\nAddress=10.10.0.1/24\nInclude=a.conf\n\n\
+             [Peer]\nName=x\nAllowedIPs=10.10.0.0/24\n",
+        )
+        .unwrap();
+        fs::write(dir.join("a.conf"), "[Peer]\nName=x\n").unwrap();
+
+        let (conf, sources) = Conf::load(&main).unwrap();
+        let files: Vec<_> = conf
+            .validate(&sources)
+            .into_iter()
+            .map(|d| d.file.unwrap())
+            .collect();
+        assert_eq!(files, vec![main, dir.join("a.conf")]);
+    }
 }
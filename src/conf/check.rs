@@ -0,0 +1,422 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ip_network::Ipv4Network;
+
+use super::{Conf, ConfError, ConfSource};
+use crate::peer::PeerName;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a conf file, pointing at the file, section (and line) it came from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub section: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(section: &SectionSource, line: Option<usize>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            file: section.file.map(Path::to_owned),
+            line,
+            section: Some(section.label()),
+            message,
+        }
+    }
+
+    fn warning(section: &SectionSource, line: Option<usize>, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            file: section.file.map(Path::to_owned),
+            line,
+            section: Some(section.label()),
+            message,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl From<ConfError> for Diagnostic {
+    fn from(err: ConfError) -> Self {
+        let (file, err) = match err {
+            ConfError::InFile(path, err) => (Some(path), *err),
+            err => (None, err),
+        };
+        Self {
+            severity: Severity::Error,
+            file,
+            line: None,
+            section: None,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{line}: ", file.display())?,
+            (Some(file), None) => write!(f, "{}: ", file.display())?,
+            (None, Some(line)) => write!(f, "line {line}: ")?,
+            (None, None) => (),
+        }
+        if let Some(ref section) = self.section {
+            write!(f, "{section}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Raw position info of a `[Section]` and its keys, serde_ini does not keep track of these
+struct SectionSource<'a> {
+    file: Option<&'a Path>,
+    name: &'a str,
+    line: Option<usize>,
+    keys: Vec<(&'a str, &'a str, Option<usize>)>,
+}
+
+impl<'a> SectionSource<'a> {
+    fn label(&self) -> String {
+        match self.get("Name") {
+            Some((name, _)) => format!("[{}] {}", self.name, name),
+            None => format!("[{}]", self.name),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<(&'a str, Option<usize>)> {
+        self.keys
+            .iter()
+            .find(|(k, _, _)| *k == key)
+            .map(|(_, v, line)| (*v, *line))
+    }
+
+    /// Line of `key` if present, or the section header line otherwise
+    fn line_of(&self, key: &str) -> Option<usize> {
+        self.get(key).map_or(self.line, |(_, line)| line)
+    }
+}
+
+fn scan_sections(source: &ConfSource) -> Vec<SectionSource<'_>> {
+    let mut sections: Vec<SectionSource> = vec![];
+
+    for (i, line) in source.text.lines().enumerate() {
+        let line_no = Some(i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push(SectionSource {
+                file: source.path.as_deref(),
+                name: name.trim(),
+                line: line_no,
+                keys: vec![],
+            });
+        } else if let (Some((key, value)), Some(section)) =
+            (line.split_once('='), sections.last_mut())
+        {
+            section.keys.push((key.trim(), value.trim(), line_no));
+        }
+    }
+
+    sections
+}
+
+/// Sections of a conf that did not come from ini text, e.g. json or toml input
+fn unlocated_sections(conf: &Conf) -> Vec<SectionSource<'_>> {
+    let section = |name, peer_name| SectionSource {
+        file: None,
+        name,
+        line: None,
+        keys: vec![("Name", peer_name, None)],
+    };
+
+    std::iter::once(section("Interface", &conf.interface.name))
+        .chain(conf.peers.iter().map(|peer| section("Peer", &peer.name)))
+        .collect()
+}
+
+/// Where `key` of `section` is defined, for pointing at it from diagnostics on other sections
+fn defined_at(section: &SectionSource, key: &str) -> String {
+    match (section.file, section.line_of(key)) {
+        (Some(file), Some(line)) => format!(" at {}:{line}", file.display()),
+        (Some(file), None) => format!(" in {}", file.display()),
+        (None, Some(line)) => format!(" on line {line}"),
+        (None, None) => String::new(),
+    }
+}
+
+impl Conf {
+    /// Semantic checks on top of what `parse_from` enforces. `sources` should be the ini files
+    /// this `Conf` was merged from (see [`Conf::load`]) so that diagnostics can point back at
+    /// lines in them, or empty if the conf was not read from ini. Sorted by file, in the order of
+    /// `sources`, and line.
+    pub fn validate(&self, sources: &[ConfSource]) -> Vec<Diagnostic> {
+        let sections: Vec<_> = if sources.is_empty() {
+            unlocated_sections(self)
+        } else {
+            sources.iter().flat_map(scan_sections).collect()
+        };
+        let Some(interface_src) = sections.iter().find(|s| s.name == "Interface") else {
+            return vec![ConfError::MissingInterface.into()];
+        };
+        let peer_srcs: Vec<_> = sections.iter().filter(|s| s.name == "Peer").collect();
+        if peer_srcs.len() != self.peers.len() {
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                file: None,
+                line: None,
+                section: None,
+                message: "conf does not match its source".to_string(),
+            }];
+        }
+
+        let mut diagnostics = vec![];
+
+        if self.interface.name.len() > PeerName::max_len() {
+            diagnostics.push(Diagnostic::error(
+                interface_src,
+                interface_src.line_of("Name"),
+                format!("name longer than {} bytes", PeerName::max_len()),
+            ));
+        }
+        if let Some(ref tun_name) = self.interface.tun_name {
+            // IFNAMSIZ includes the terminating nul
+            if tun_name.is_empty() || tun_name.len() > 15 {
+                diagnostics.push(Diagnostic::error(
+                    interface_src,
+                    interface_src.line_of("TunName"),
+                    "tun name must be 1 to 15 bytes long".to_string(),
+                ));
+            } else if tun_name.contains(|c: char| c == '/' || c.is_whitespace()) {
+                diagnostics.push(Diagnostic::error(
+                    interface_src,
+                    interface_src.line_of("TunName"),
+                    format!("invalid tun name {tun_name:?}"),
+                ));
+            }
+        }
+        let (if_addr, if_cidr) = self.interface.address;
+        if if_cidr > 32 {
+            diagnostics.push(Diagnostic::error(
+                interface_src,
+                interface_src.line_of("Address"),
+                format!("prefix length of {if_addr}/{if_cidr} must be in the range 0-32"),
+            ));
+        }
+        if let Some(mtu) = self.interface.mtu {
+            // the minimum an IPv4 link must carry
+            if mtu < 576 {
+                diagnostics.push(Diagnostic::error(
+                    interface_src,
+                    interface_src.line_of("Mtu"),
+                    format!("mtu {mtu} is below the IPv4 minimum of 576"),
+                ));
+            }
+        }
+
+        let mut names: HashMap<&str, &SectionSource> = HashMap::new();
+        let mut networks = Vec::with_capacity(self.peers.len());
+        for (peer, src) in self.peers.iter().zip(peer_srcs.iter()) {
+            let name_line = src.line_of("Name");
+
+            if peer.name.is_empty() {
+                diagnostics.push(Diagnostic::error(src, name_line, "empty name".to_string()));
+            } else if peer.name.len() > PeerName::max_len() {
+                diagnostics.push(Diagnostic::error(
+                    src,
+                    name_line,
+                    format!("name longer than {} bytes", PeerName::max_len()),
+                ));
+            }
+            if peer.name == self.interface.name {
+                diagnostics.push(Diagnostic::error(
+                    src,
+                    name_line,
+                    "peer has the same name as the interface".to_string(),
+                ));
+            }
+            match names.entry(&peer.name) {
+                Entry::Occupied(first) => diagnostics.push(Diagnostic::error(
+                    src,
+                    name_line,
+                    format!(
+                        "duplicate peer name, first defined{}",
+                        defined_at(first.get(), "Name")
+                    ),
+                )),
+                Entry::Vacant(entry) => {
+                    entry.insert(src);
+                }
+            }
+
+            if let Some((endpoint, line)) = src.get("Endpoint") {
+                if SocketAddrV4::from_str(endpoint).is_err() {
+                    diagnostics.push(Diagnostic::warning(
+                        src,
+                        line,
+                        format!("invalid endpoint {endpoint:?} is ignored"),
+                    ));
+                }
+            }
+
+            let mut peer_networks = Vec::with_capacity(peer.allowed_ips.len());
+            for &(ip, cidr) in &peer.allowed_ips {
+                let Ok(net) = Ipv4Network::new_truncate(ip, cidr) else {
+                    diagnostics.push(Diagnostic::error(
+                        src,
+                        src.line_of("AllowedIPs"),
+                        format!("prefix length of {ip}/{cidr} must be in the range 0-32"),
+                    ));
+                    continue;
+                };
+                if net.contains(if_addr) {
+                    diagnostics.push(Diagnostic::warning(
+                        src,
+                        src.line_of("AllowedIPs"),
+                        format!("interface address {if_addr} is inside allowed ip {ip}/{cidr}"),
+                    ));
+                }
+                peer_networks.push(net);
+            }
+            networks.push(peer_networks);
+        }
+
+        for (i, src) in peer_srcs.iter().enumerate() {
+            for (j, (other, other_src)) in self.peers[..i].iter().zip(peer_srcs.iter()).enumerate()
+            {
+                for net in &networks[i] {
+                    for other_net in &networks[j] {
+                        if net == other_net {
+                            diagnostics.push(Diagnostic::error(
+                                src,
+                                src.line_of("AllowedIPs"),
+                                format!(
+                                    "allowed ip {net} is also claimed by peer {}{}",
+                                    other.name,
+                                    defined_at(other_src, "AllowedIPs")
+                                ),
+                            ));
+                        } else if net.contains(other_net.network_address())
+                            || other_net.contains(net.network_address())
+                        {
+                            diagnostics.push(Diagnostic::warning(
+                                src,
+                                src.line_of("AllowedIPs"),
+                                format!(
+                                    "allowed ip {net} overlaps {other_net} of peer {}{}",
+                                    other.name,
+                                    defined_at(other_src, "AllowedIPs")
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        // merge order rather than by path, a later file overrides an earlier one
+        let order: HashMap<_, _> = sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| (source.path.as_deref(), i))
+            .collect();
+        diagnostics.sort_by_key(|d| (order.get(&d.file.as_deref()).copied(), d.line));
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(input: &str) -> Vec<Diagnostic> {
+        let source = ConfSource {
+            path: None,
+            text: input.to_string(),
+        };
+        Conf::parse_from(input).unwrap().validate(&[source])
+    }
+
+    #[test]
+    fn test_valid_conf() {
+        let input = r#"
+[Interface]
+Name=server
+Address=10.10.0.1/24
+
+[Peer]
+Name=client-B
+AllowedIPs=10.10.0.2/32
+
+[Peer]
+Name=client-A
+AllowedIPs=10.10.0.3/32
+"#;
+        assert_eq!(check(input), vec![]);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let input = r#"
+[Interface]
+Name=client
+Address=10.10.0.2/24
+
+[Peer]
+Name=server
+Endpoint=172.18.0.2
+AllowedIPs=10.10.0.0/24
+
+[Peer]
+Name=server
+AllowedIPs=10.10.0.1/24, 10.10.0.3/32
+"#;
+        let diagnostics = check(input);
+        let summary: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.line, d.section.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Warning, Some(8), Some("[Peer] server")),
+                (Severity::Warning, Some(9), Some("[Peer] server")),
+                (Severity::Error, Some(12), Some("[Peer] server")),
+                (Severity::Warning, Some(13), Some("[Peer] server")),
+                (Severity::Error, Some(13), Some("[Peer] server")),
+                (Severity::Warning, Some(13), Some("[Peer] server")),
+            ]
+        );
+        assert_eq!(
+            diagnostics[2].to_string(),
+            "error: line 12: [Peer] server: duplicate peer name, first defined on line 7"
+        );
+    }
+}
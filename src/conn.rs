@@ -0,0 +1,226 @@
+use std::net::{SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::peer::{Endpoint, Peer};
+use crate::poll::{Poll, Token};
+use crate::transport::Transport;
+
+/// Lifecycle of the connected per-peer transports (sockets, for UDP). A peer's socket is replaced
+/// when its endpoint moves, with the epoll registration changed under the peer's endpoint update,
+/// so concurrent moves of one peer are applied one after the other. Replaced sockets may still be
+/// in use by other threads (receiving, or queued in a send batch), so they are only retired:
+/// closed by `reclaim` once no other reference is left.
+pub struct Conns<T = UdpSocket> {
+    /// Connect peers to their endpoint at all, otherwise they are reached via the listening socket
+    enabled: bool,
+    retired: Mutex<Vec<Arc<T>>>,
+}
+
+impl<T: Transport> Conns<T> {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Moves `peer` to `addr`, replacing its connected socket with one made by `listener` and
+    /// registered in `poll` as `token`. Returns whether the endpoint changed.
+    pub fn roam<ID: From<i32> + Into<i32> + Copy>(
+        &self,
+        poll: &Poll,
+        listener: &T,
+        peer: &Peer<T>,
+        addr: SocketAddrV4,
+        token: Token<ID>,
+    ) -> bool {
+        if peer.endpoint().addr == Some(addr) {
+            return false;
+        }
+
+        peer.update_endpoint(|endpoint| {
+            if endpoint.addr == Some(addr) {
+                return None;
+            }
+            if let Some(ref conn) = endpoint.conn {
+                self.retire(poll, conn);
+            }
+            let conn = if self.enabled {
+                self.connect(poll, listener, peer, addr, token)
+            } else {
+                None
+            };
+
+            Some(Endpoint {
+                addr: Some(addr),
+                conn,
+            })
+        })
+    }
+
+    /// Retires the connected socket of `peer`, e.g. when it is removed
+    pub fn detach(&self, poll: &Poll, peer: &Peer<T>) {
+        peer.update_endpoint(|endpoint| {
+            let conn = endpoint.conn.as_ref()?;
+            self.retire(poll, conn);
+
+            Some(Endpoint {
+                addr: endpoint.addr,
+                conn: None,
+            })
+        });
+    }
+
+    /// Forgets where `peer` is, retiring its connected socket, e.g. when its `Endpoint` is
+    /// removed from the conf
+    pub fn forget(&self, poll: &Poll, peer: &Peer<T>) {
+        peer.update_endpoint(|endpoint| {
+            endpoint.addr?;
+            if let Some(ref conn) = endpoint.conn {
+                self.retire(poll, conn);
+            }
+
+            Some(Endpoint::default())
+        });
+    }
+
+    /// Closes retired sockets no longer used by any thread, returns how many are left
+    pub fn reclaim(&self) -> usize {
+        let mut retired = self.retired.lock();
+        retired.retain(|conn| Arc::strong_count(conn) > 1);
+
+        retired.len()
+    }
+
+    fn connect<ID: From<i32> + Into<i32> + Copy>(
+        &self,
+        poll: &Poll,
+        listener: &T,
+        peer: &Peer<T>,
+        addr: SocketAddrV4,
+        token: Token<ID>,
+    ) -> Option<Arc<T>> {
+        let conn = listener.connect(addr).and_then(|conn| match conn {
+            Some(conn) => poll.register_read(token, &conn).map(|_| Some(conn)),
+            None => Ok(None),
+        });
+        let conn = match conn {
+            Ok(conn) => conn?,
+            Err(err) => {
+                // packets go out via the listening socket instead
+                tracing::error!("error connecting to peer: {:?}", err);
+                return None;
+            }
+        };
+        if peer.is_backlogged() {
+            if let Err(err) = poll.watch_writable(token, &conn, true) {
+                tracing::debug!("failed to watch socket: {:?}", err);
+            }
+        }
+
+        Some(Arc::new(conn))
+    }
+
+    fn retire(&self, poll: &Poll, conn: &Arc<T>) {
+        if let Err(err) = poll.delete(conn.as_ref()) {
+            tracing::debug!("failed to unregister connected socket: {:?}", err);
+        }
+        self.retired.lock().push(Arc::clone(conn));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::poll::Events;
+
+    fn local_addr(sock: &UdpSocket) -> SocketAddrV4 {
+        let SocketAddr::V4(addr) = sock.local_addr().unwrap() else {
+            unreachable!()
+        };
+        addr
+    }
+
+    #[test]
+    fn test_roam_under_traffic() {
+        let poll = Poll::new().unwrap();
+        let conns = Conns::new(true);
+        let listener = crate::udp::new_socket(0, None, false).unwrap();
+        let peer: Peer = Peer::new();
+        let token = Token::Sock(7);
+
+        let remotes: Vec<UdpSocket> = (0..4)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for remote in &remotes {
+            remote.set_nonblocking(true).unwrap();
+        }
+        let addrs: Vec<SocketAddrV4> = remotes.iter().map(local_addr).collect();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            // senders use whatever socket the peer has, like the event loops do
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut buf = [0u8; 64];
+                    while !done.load(Ordering::Relaxed) {
+                        let endpoint = peer.endpoint();
+                        if let Some(ref conn) = endpoint.conn {
+                            let _ = conn.send(b"data");
+                            let _ = conn.recv(&mut buf);
+                        }
+                        for remote in &remotes {
+                            let _ = remote.recv_from(&mut buf);
+                        }
+                    }
+                });
+            }
+            let roamers: Vec<_> = (0..2)
+                .map(|r| {
+                    let (conns, poll, listener, peer) = (&conns, &poll, &listener, &peer);
+                    let addrs = &addrs;
+                    s.spawn(move || {
+                        for i in 0..200 {
+                            let addr = addrs[(i + r) % addrs.len()];
+                            conns.roam(poll, listener, peer, addr, token);
+                            conns.reclaim();
+                        }
+                    })
+                })
+                .collect();
+            for roamer in roamers {
+                roamer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        // only the socket of the final endpoint is still registered
+        let endpoint = peer.endpoint();
+        let conn = endpoint.conn.as_ref().unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), endpoint.addr.unwrap().into());
+        let remote = remotes
+            .iter()
+            .find(|r| Some(local_addr(r)) == endpoint.addr);
+        remote
+            .unwrap()
+            .send_to(b"x", conn.local_addr().unwrap())
+            .unwrap();
+
+        let mut events = Events::with_capacity(8);
+        assert_eq!(poll.wait(&mut events, 1000).unwrap(), 1);
+        let tokens: Vec<Token> = events.iter().collect();
+        assert_eq!(tokens, [token]);
+        drop(endpoint);
+
+        assert_eq!(conns.reclaim(), 0);
+        conns.detach(&poll, &peer);
+        assert!(peer.endpoint().conn.is_none());
+        assert_eq!(conns.reclaim(), 0);
+    }
+}
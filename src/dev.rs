@@ -1,37 +1,187 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
-use std::os::fd::{AsRawFd, BorrowedFd};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+#[cfg(feature = "io-uring")]
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
 
-use tun_tap::Iface;
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
 
 use crate::allowed_ip::AllowedIps;
-use crate::packet::Packet;
+use crate::conf::Conf;
+use crate::conn::Conns;
+use crate::offload::{
+    finish_checksum, split_tso, TcpCoalescer, MAX_SUPER_PACKET, VIRTIO_NET_HDR_GSO_TCPV4,
+};
+use crate::packet::{Packet, DATA_HEADER_LEN};
 use crate::peer::{Action, Peer, PeerName};
-use crate::poll::{Poll, Token};
-use crate::udp;
+use crate::poll::{Events, Poll, Token, Waker};
+use crate::timer::Timers;
+use crate::transport::Transport;
+use crate::tun::{Tun, TunConfig};
+use crate::tunnel::{TunnelInterface, TunnelQueue};
+use crate::udp::{self, Dest, RecvBatch, SendBatch};
+#[cfg(feature = "io-uring")]
+use crate::uring::{range_in, Completion, Ring};
+
+/// I/O backend of the event loops
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum Backend {
+    #[default]
+    Epoll,
+    /// io_uring for tun reads and receives on the listening socket, everything else still goes
+    /// through epoll. Requires the `io-uring` feature and connected peer sockets.
+    Uring,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "epoll" => Ok(Backend::Epoll),
+            "uring" => Ok(Backend::Uring),
+            _ => Err(format!("unknown backend: {s}, expected epoll or uring")),
+        }
+    }
+}
 
 pub struct DeviceConfig<'a> {
     pub name: PeerName,
     pub use_connected_peer: bool,
     pub listen_port: u16,
-    pub tun_name: &'a str,
+    pub tun: TunConfig<'a>,
     pub fwmark: Option<u32>,
+    pub backend: Backend,
 }
 
-pub struct Device {
+pub struct Device<T = UdpSocket, I = Tun> {
     name: PeerName,
-    udp: Arc<UdpSocket>,
-    iface: Iface,
+    /// Listening sockets on the same port, one per tun queue. The kernel steers the packets of
+    /// a peer to one of them, see `udp::new_sockets`.
+    udp: Vec<T>,
+    iface: I,
+    /// Connected sockets and timers, nested in the poll of every event loop thread
     poll: Poll,
-    peers_by_name: HashMap<PeerName, Arc<Peer>>,
-    peers_by_index: Vec<Arc<Peer>>,
-    peers_by_ip: AllowedIps<Arc<Peer>>,
+    /// Poll of the event loop threads of each listening socket: that socket, the tun queue of
+    /// the same index, the waker and the shared poll
+    polls: Vec<Poll>,
+    /// Looked up on every packet without locking, replaced as a whole by `reload`
+    peers: ArcSwap<Peers<T>>,
+    /// Held by `reload` so that concurrent reloads do not lose each other's changes
+    reloading: Mutex<()>,
+    /// Called with the endpoints of all peers whenever they change, see `on_endpoints_changed`
+    endpoints_changed: Mutex<Option<EndpointsChanged>>,
+    timers: Timers,
+
+    /// Peers with packets waiting for their socket to become writable
+    backlogged: Mutex<Vec<Arc<Peer<T>>>>,
+    has_backlog: AtomicBool,
 
-    use_connected_peer: bool,
+    waker: Waker,
+    is_shutdown: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+
+    conns: Conns<T>,
     listen_port: u16,
-    fwmark: Option<u32>,
+    offload: bool,
+    backend: Backend,
+}
+
+type EndpointsChanged = Box<dyn FnMut(&[SocketAddrV4]) + Send>;
+
+/// Peer lookup tables, swapped out as a whole on conf reload
+struct Peers<T> {
+    by_name: HashMap<PeerName, Arc<Peer<T>>>,
+    by_index: HashMap<u32, Arc<Peer<T>>>,
+    /// Shared with the `Peers` it was cloned from until allowed ips change, see `by_ip_mut`
+    by_ip: Arc<AllowedIps<Arc<Peer<T>>>>,
+    /// Endpoints set by the conf, as opposed to learned from the peers' packets
+    conf_endpoints: HashMap<PeerName, SocketAddrV4>,
+    // indices are never reused, so that packets addressed to a removed peer cannot reach a new one
+    next_idx: u32,
+}
+
+impl<T> Default for Peers<T> {
+    fn default() -> Self {
+        Self {
+            by_name: HashMap::new(),
+            by_index: HashMap::new(),
+            by_ip: Arc::new(AllowedIps::new()),
+            conf_endpoints: HashMap::new(),
+            next_idx: 0,
+        }
+    }
+}
+
+impl<T> Clone for Peers<T> {
+    fn clone(&self) -> Self {
+        Self {
+            by_name: self.by_name.clone(),
+            by_index: self.by_index.clone(),
+            by_ip: Arc::clone(&self.by_ip),
+            conf_endpoints: self.conf_endpoints.clone(),
+            next_idx: self.next_idx,
+        }
+    }
+}
+
+impl<T> Peers<T> {
+    fn insert(&mut self, name: PeerName, mut peer: Peer<T>) -> Arc<Peer<T>> {
+        peer.set_local_idx(self.next_idx);
+        self.next_idx += 1;
+
+        let peer = Arc::new(peer);
+
+        self.by_name.insert(name, Arc::clone(&peer));
+        self.by_index.insert(peer.local_idx(), Arc::clone(&peer));
+        // a new peer has no entries to replace
+        self.by_ip_mut().extend(
+            peer.allowed_ips()
+                .iter()
+                .map(|(_, ip, cidr)| (ip, cidr, Arc::clone(&peer))),
+        );
+
+        peer
+    }
+
+    fn remove(&mut self, name: &PeerName) -> Option<Arc<Peer<T>>> {
+        let peer = self.by_name.remove(name)?;
+        self.conf_endpoints.remove(name);
+        self.by_index.remove(&peer.local_idx());
+        self.by_ip_mut().remove(&|p| Arc::ptr_eq(p, &peer));
+
+        Some(peer)
+    }
+
+    fn index_allowed_ips(&mut self, peer: &Arc<Peer<T>>) {
+        let by_ip = self.by_ip_mut();
+        by_ip.remove(&|p| Arc::ptr_eq(p, peer));
+        by_ip.extend(
+            peer.allowed_ips()
+                .iter()
+                .map(|(_, ip, cidr)| (ip, cidr, Arc::clone(peer))),
+        );
+    }
+
+    /// `by_ip` to change, copied first if a published `Peers` still looks it up
+    fn by_ip_mut(&mut self) -> &mut AllowedIps<Arc<Peer<T>>> {
+        if Arc::get_mut(&mut self.by_ip).is_none() {
+            let mut by_ip = AllowedIps::new();
+            by_ip.extend(
+                self.by_ip
+                    .iter()
+                    .map(|(peer, ip, cidr)| (ip, cidr, Arc::clone(peer))),
+            );
+            self.by_ip = Arc::new(by_ip);
+        }
+        Arc::get_mut(&mut self.by_ip).unwrap()
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -60,106 +210,520 @@ impl From<SockID> for i32 {
 }
 
 const BUF_SIZE: usize = 1504;
+/// Room for a packet of up to `BUF_SIZE` bytes, encapsulated
+const DST_BUF_SIZE: usize = BUF_SIZE + DATA_HEADER_LEN;
+/// Packets read from one fd before the other ready fds get their turn
+const BUDGET: usize = 64;
+/// Events taken from a poll at once
+const MAX_EVENTS: usize = 64;
+
+struct ThreadData<T> {
+    src_buf: Vec<u8>,
+    dst_buf: [u8; DST_BUF_SIZE],
+    seg_buf: [u8; BUF_SIZE],
+    recv: RecvBatch,
+    out: Outgoing<T>,
+}
 
-struct ThreadData {
-    src_buf: [u8; BUF_SIZE],
-    dst_buf: [u8; BUF_SIZE],
+/// Packets produced while handling a batch of events, written out together at the end of it
+struct Outgoing<T> {
+    udp: SendBatch<T>,
+    /// Peer of each packet in `udp`, to backlog the ones that do not fit in the socket buffer
+    peers: Vec<Arc<Peer<T>>>,
+    tun: TcpCoalescer,
+    /// Tun queue of the thread
+    queue: usize,
 }
 
 impl Device {
     pub fn new(config: DeviceConfig) -> io::Result<Self> {
-        let iface = tun_tap::Iface::without_packet_info(config.tun_name, tun_tap::Mode::Tun)?;
-        iface.set_non_blocking()?;
+        let iface = Tun::open(&config.tun)?;
+        // a tun with offloads hands out super-packets, which are best sent with udp offloads
+        let offload = iface.vnet_hdr();
+        let udp = udp::new_sockets(
+            config.listen_port,
+            config.fwmark,
+            offload,
+            iface.num_queues(),
+        )?;
+
+        Self::with_iface(config, iface, udp)
+    }
+}
+
+impl<T: Transport> Device<T> {
+    /// Like `new`, but peers are reached through `transports` instead of UDP sockets, one per
+    /// tun queue. `listen_port` and `fwmark` of `config` are left to the transports.
+    pub fn with_transports(config: DeviceConfig, transports: Vec<T>) -> io::Result<Self> {
+        let iface = Tun::open(&config.tun)?;
+
+        Self::with_interface(config, iface, transports)
+    }
+}
+
+impl<T: Transport, I: TunnelInterface> Device<T, I> {
+    /// Like `with_transports`, but inner packets are read from and written to `iface` instead
+    /// of a tun opened as configured by `config.tun`
+    pub fn with_interface(config: DeviceConfig, iface: I, transports: Vec<T>) -> io::Result<Self> {
+        if config.backend == Backend::Uring {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "io_uring sends and receives on UDP sockets only",
+            ));
+        }
+        if transports.len() != iface.num_queues() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected one transport per interface queue",
+            ));
+        }
+
+        Self::with_iface(config, iface, transports)
+    }
+
+    fn with_iface(config: DeviceConfig, iface: I, udp: Vec<T>) -> io::Result<Self> {
+        let offload = iface.vnet_hdr();
+        match config.backend {
+            Backend::Uring if !cfg!(feature = "io-uring") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "built without the io-uring feature",
+                ));
+            }
+            Backend::Uring if offload => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "offloads are not supported with io_uring",
+                ));
+            }
+            // backlogs wait for their socket to become writable, and only connected sockets
+            // are polled for it next to the ring
+            Backend::Uring if !config.use_connected_peer => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "io_uring requires connected peer sockets",
+                ));
+            }
+            _ => (),
+        }
 
         let poll = Poll::new()?;
-        let use_connected_peer = config.use_connected_peer;
         let listen_port = config.listen_port;
+        let conns = Conns::new(config.use_connected_peer);
 
-        let udp = Arc::new(udp::new_socket(config.listen_port, config.fwmark)?);
+        let polls = (0..udp.len())
+            .map(|i| {
+                let poll = Poll::new()?;
+                poll.register_read::<_, SockID>(Token::Tun, iface.queue(i))?;
+                Ok(poll)
+            })
+            .collect::<io::Result<_>>()?;
 
         Ok(Self {
             name: config.name,
             iface,
             udp,
             poll,
-            peers_by_name: HashMap::new(),
-            peers_by_index: Vec::new(),
-            peers_by_ip: AllowedIps::new(),
-            use_connected_peer,
+            polls,
+            peers: ArcSwap::from_pointee(Peers::default()),
+            reloading: Mutex::new(()),
+            endpoints_changed: Mutex::new(None),
+            timers: Timers::new()?,
+            backlogged: Mutex::new(Vec::new()),
+            has_backlog: AtomicBool::new(false),
+            waker: Waker::new()?,
+            is_shutdown: AtomicBool::new(false),
+            threads: Mutex::new(Vec::new()),
+            conns,
             listen_port,
-            fwmark: config.fwmark,
+            offload,
+            backend: config.backend,
         })
     }
 
-    pub fn add_peer(&mut self, name: PeerName, mut peer: Peer) {
-        let local_idx = self.peers_by_index.len();
-        peer.set_local_idx(local_idx as u32);
+    /// Name of the tun interface, as picked by the kernel if a `%d` pattern was configured
+    pub fn tun_name(&self) -> &str {
+        self.iface.name()
+    }
 
-        let peer = Arc::new(peer);
+    /// Endpoints of all peers, configured or learned
+    pub fn endpoints(&self) -> Vec<SocketAddrV4> {
+        let peers = self.peers.load();
+        peers
+            .by_name
+            .values()
+            .filter_map(|peer| peer.endpoint().addr)
+            .collect()
+    }
 
-        self.peers_by_name.insert(name, Arc::clone(&peer));
-        self.peers_by_ip.extend(
-            peer.allowed_ips()
-                .iter()
-                .map(|(_, ip, cidr)| (ip, cidr, Arc::clone(&peer))),
-        );
-        self.peers_by_index.push(peer);
+    /// Sets `f` to be called with `endpoints` whenever a peer roams, or a reload changes them.
+    /// It runs on the thread that saw the change, e.g. an event loop.
+    pub fn on_endpoints_changed(&self, f: impl FnMut(&[SocketAddrV4]) + Send + 'static) {
+        *self.endpoints_changed.lock() = Some(Box::new(f));
     }
 
-    pub fn event_loop(&self, i: usize) {
-        tracing::trace!("event loop, thread={i}");
-        let mut t = ThreadData {
-            src_buf: [0; BUF_SIZE],
-            dst_buf: [0; BUF_SIZE],
-        };
+    fn endpoints_changed(&self) {
+        // the endpoints are read under the lock, so a later change is never overwritten by
+        // an earlier one
+        let mut f = self.endpoints_changed.lock();
+        if let Some(f) = f.as_mut() {
+            f(&self.endpoints());
+        }
+    }
+
+    pub fn add_peers(&mut self, new_peers: impl IntoIterator<Item = (PeerName, Peer<T>)>) {
+        let mut peers = Peers::clone(&self.peers.load());
+        for (name, peer) in new_peers {
+            if let Some(endpoint) = peer.endpoint().addr {
+                peers.conf_endpoints.insert(name.clone(), endpoint);
+            }
+            peers.insert(name, peer);
+        }
+        self.peers.store(Arc::new(peers));
+    }
 
-        while let Ok(token) = self.poll.wait() {
-            tracing::trace!("epoll: {:?} @ {i}", token);
+    /// Applies the peers of a re-read `conf` to the running device: peers missing from it are
+    /// removed, new ones are added, and allowed ips and endpoints of the rest are updated in
+    /// place so that peers which did not change keep their sessions. A peer whose `Endpoint` was
+    /// removed forgets it, until it reaches the device from wherever it is.
+    pub fn reload(&self, conf: &Conf) -> io::Result<()> {
+        if PeerName::new(&conf.interface.name).ok().as_ref() != Some(&self.name) {
+            tracing::warn!("interface name changed, restart to apply");
+        }
+        if conf.interface.listen_port != self.listen_port {
+            tracing::warn!("listen port changed, restart to apply");
+        }
+
+        let mut wanted = Vec::with_capacity(conf.peers.len());
+        for peer_conf in &conf.peers {
+            let name = PeerName::new(&peer_conf.name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            wanted.push((name, peer_conf));
+        }
+
+        let _reloading = self.reloading.lock();
+        let mut peers = Peers::clone(&self.peers.load());
 
-            match token {
-                Token::Tun => {
-                    if let Err(err) = self.handle_tun(&mut t) {
-                        tracing::error!("tun error {:?}", err);
+        let keep: HashSet<&PeerName> = wanted.iter().map(|(name, _)| name).collect();
+        let removed: Vec<PeerName> = peers
+            .by_name
+            .keys()
+            .filter(|name| !keep.contains(name))
+            .cloned()
+            .collect();
+        for name in removed {
+            let Some(peer) = peers.remove(&name) else {
+                continue;
+            };
+            self.conns.detach(&self.poll, &peer);
+            tracing::info!("removed peer {name}");
+        }
+
+        let mut endpoint_changed = vec![];
+        let mut endpoint_removed = vec![];
+        let mut added = vec![];
+        for (name, peer_conf) in wanted {
+            let peer = match peers.by_name.get(&name) {
+                Some(peer) => {
+                    let peer = Arc::clone(peer);
+                    if !same_allowed_ips(&peer, &peer_conf.allowed_ips) {
+                        peer.set_allowed_ips(&peer_conf.allowed_ips);
+                        peers.index_allowed_ips(&peer);
+                        tracing::info!("updated allowed ips of peer {name}");
                     }
+                    peer
                 }
-                Token::Sock(SockID::Disconnected) => {
-                    if let Err(err) = self.handle_udp(&self.udp, &mut t) {
-                        tracing::error!("udp error {:?}", err);
+                None => {
+                    let mut peer = Peer::default();
+                    for (ip, cidr) in &peer_conf.allowed_ips {
+                        peer.add_allowed_ip(*ip, *cidr);
                     }
+                    tracing::info!("added peer {name}");
+                    let peer = peers.insert(name.clone(), peer);
+                    added.push(peer.local_idx());
+                    peer
                 }
-                Token::Sock(SockID::ConnectedPeer(i)) => {
-                    let Some(peer) = self.peers_by_index.get(i as usize) else {
-                        continue;
-                    };
-                    if let Some(conn) = peer.endpoint().conn.as_deref() {
-                        if let Err(err) = self.handle_connected_peer(conn, peer, &mut t) {
-                            tracing::error!("udp error {:?}", err);
+            };
+            match peer_conf.endpoint {
+                Some(endpoint) => {
+                    peers.conf_endpoints.insert(name, endpoint);
+                    if peer.endpoint().addr != Some(endpoint) {
+                        endpoint_changed.push((peer, endpoint));
+                    }
+                }
+                None => {
+                    if peers.conf_endpoints.remove(&name).is_some() {
+                        tracing::info!("removed endpoint of peer {name}");
+                        endpoint_removed.push(peer);
+                    }
+                }
+            }
+        }
+        self.peers.store(Arc::new(peers));
+
+        let mut buf = [0u8; BUF_SIZE];
+        for (peer, endpoint) in endpoint_changed {
+            self.roam(&peer, endpoint);
+            self.take_action(&peer, peer.send_handshake(self.name.as_ref(), &mut buf));
+        }
+        for peer in endpoint_removed {
+            self.conns.forget(&self.poll, &peer);
+        }
+        self.endpoints_changed();
+        for idx in added {
+            self.timers.schedule(Instant::now(), idx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles events of tun queue `i` and the shared sockets and timers until `shutdown`
+    pub fn event_loop(&self, i: usize) {
+        tracing::trace!("event loop, thread={i}");
+        let poll = &self.polls[i % self.polls.len()];
+
+        let mut t = self.thread_data(i);
+        let mut events = Events::with_capacity(MAX_EVENTS);
+        let mut shared = Events::with_capacity(MAX_EVENTS);
+        // fds with packets left when their budget ran out, edge triggered epoll will not report
+        // them again
+        let mut ready = VecDeque::new();
+
+        'events: loop {
+            let timeout = if ready.is_empty() { -1 } else { 0 };
+            if let Err(err) = poll.wait(&mut events, timeout) {
+                tracing::error!("epoll error {:?}", err);
+                break;
+            }
+            for token in events.iter() {
+                tracing::trace!("epoll: {:?} @ {i}", token);
+                match token {
+                    Token::Waker => {
+                        if self.is_shutdown.load(Ordering::Acquire) {
+                            break 'events;
                         }
                     }
+                    Token::Shared => self.take_shared(&mut shared, &mut ready),
+                    token => queue_ready(&mut ready, token),
                 }
             }
+            self.run_ready(&mut ready, &mut t);
         }
+        tracing::trace!("event loop exited, thread={i}");
+    }
+
+    /// Queues the events of the shared poll. It only wakes one thread per new event, so that
+    /// one takes all of them.
+    fn take_shared(&self, events: &mut Events, ready: &mut VecDeque<Token<SockID>>) {
+        loop {
+            let n = match self.poll.wait(events, 0) {
+                Ok(n) => n,
+                Err(err) => {
+                    tracing::error!("epoll error {:?}", err);
+                    return;
+                }
+            };
+            for token in events.iter() {
+                tracing::trace!("shared epoll: {:?}", token);
+                queue_ready(ready, token);
+            }
+            if n < events.capacity() {
+                return;
+            }
+        }
+    }
+
+    /// Gives every ready fd one budget, the ones with packets left are queued again behind the
+    /// others
+    fn run_ready(&self, ready: &mut VecDeque<Token<SockID>>, thread_data: &mut ThreadData<T>) {
+        for _ in 0..ready.len() {
+            let Some(token) = ready.pop_front() else {
+                break;
+            };
+            if self.handle_event(token, thread_data) {
+                ready.push_back(token);
+            }
+        }
+    }
+
+    fn thread_data(&self, i: usize) -> ThreadData<T> {
+        // with offloads both tun reads and udp receives may be up to 64 KiB
+        let buf_size = if self.offload {
+            MAX_SUPER_PACKET
+        } else {
+            BUF_SIZE
+        };
+        ThreadData {
+            src_buf: vec![0; buf_size],
+            dst_buf: [0; DST_BUF_SIZE],
+            seg_buf: [0; BUF_SIZE],
+            recv: RecvBatch::new(buf_size),
+            out: Outgoing {
+                udp: SendBatch::new(self.offload),
+                peers: Vec::new(),
+                tun: TcpCoalescer::default(),
+                queue: i,
+            },
+        }
+    }
+
+    /// Handles up to `BUDGET` packets of the fd of `token`, returns `true` if it has more
+    fn handle_event(&self, token: Token<SockID>, thread_data: &mut ThreadData<T>) -> bool {
+        match token {
+            Token::Tun => self.handle_tun(thread_data).unwrap_or_else(|err| {
+                tracing::error!("tun error {:?}", err);
+                false
+            }),
+            Token::Sock(SockID::Disconnected) => {
+                let more = self
+                    .handle_udp(self.listener(thread_data.out.queue), thread_data)
+                    .unwrap_or_else(|err| {
+                        tracing::error!("udp error {:?}", err);
+                        false
+                    });
+                self.drain_backlogs();
+                more
+            }
+            Token::Sock(SockID::ConnectedPeer(i)) => {
+                let Some(peer) = self.peers.load().by_index.get(&i).cloned() else {
+                    return false;
+                };
+                let conn = peer.endpoint().conn.clone();
+                let more = match conn {
+                    Some(conn) => self
+                        .handle_connected_peer(&conn, &peer, thread_data)
+                        .unwrap_or_else(|err| {
+                            tracing::error!("udp error {:?}", err);
+                            false
+                        }),
+                    None => false,
+                };
+                self.drain_backlogs();
+                more
+            }
+            Token::Timer => {
+                if let Err(err) = self.handle_timers(thread_data) {
+                    tracing::error!("timer error {:?}", err);
+                }
+                // in case a writability event got lost, e.g. when the endpoint changed
+                self.drain_backlogs();
+                false
+            }
+            Token::Waker | Token::Shared => false,
+        }
+    }
+
+    /// Listening socket of event loop `i`
+    fn listener(&self, i: usize) -> &T {
+        &self.udp[i % self.udp.len()]
+    }
+
+    /// Runs `event_loop` on `num_threads` new threads, these are joined by `shutdown`. Threads
+    /// beyond the number of tun queues share a queue, listening socket and poll with another
+    /// thread.
+    pub fn spawn_event_loops(self: &Arc<Self>, num_threads: usize) -> io::Result<()> {
+        let mut threads = self.threads.lock();
+        for i in 0..num_threads {
+            let dev = Arc::clone(self);
+            let thread = std::thread::Builder::new()
+                .name(format!("wontun-{i}"))
+                .spawn(move || match dev.backend {
+                    Backend::Epoll => dev.event_loop(i),
+                    #[cfg(feature = "io-uring")]
+                    Backend::Uring => dev.uring_event_loop(i),
+                    #[cfg(not(feature = "io-uring"))]
+                    Backend::Uring => unreachable!("rejected by Device::new"),
+                })?;
+            threads.push(thread);
+        }
+
+        Ok(())
+    }
+
+    /// Stops all event loops and waits for the threads started by `spawn_event_loops` to exit,
+    /// then notifies connected peers that the sessions are over.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.is_shutdown.store(true, Ordering::Release);
+        self.waker.wake()?;
+
+        let threads = std::mem::take(&mut *self.threads.lock());
+        for thread in threads {
+            if thread.join().is_err() {
+                tracing::error!("event loop thread panicked");
+            }
+        }
+
+        let mut buf = [0u8; BUF_SIZE];
+        for (name, peer) in self.peers.load().by_name.iter() {
+            self.take_action(peer, peer.send_disconnect(&mut buf));
+            if peer.dropped() > 0 {
+                tracing::info!("peer {name}: dropped {} outgoing packets", peer.dropped());
+            }
+        }
+
+        Ok(())
     }
 
     pub fn start(&self) -> io::Result<()> {
+        for (poll, udp) in self.polls.iter().zip(&self.udp) {
+            poll.register_waker(&self.waker)?;
+            poll.register_shared(&self.poll)?;
+            // with io_uring every event loop keeps receives on its listening socket in flight
+            if self.backend == Backend::Epoll {
+                poll.register_read(Token::Sock(SockID::Disconnected), udp)?;
+            }
+        }
         self.poll
-            .register_read(Token::Sock(SockID::Disconnected), self.udp.as_ref())?;
-
-        let tun_fd = unsafe { BorrowedFd::borrow_raw(self.iface.as_raw_fd()) };
-        self.poll.register_read::<_, SockID>(Token::Tun, &tun_fd)?;
+            .register_read::<_, SockID>(Token::Timer, &self.timers)?;
 
         let mut buf = [0u8; BUF_SIZE];
-        for (_, peer) in self.peers_by_name.iter() {
+        for (_, peer) in self.peers.load().by_name.iter() {
             self.take_action(peer, peer.send_handshake(self.name.as_ref(), &mut buf));
+            self.timers.schedule(Instant::now(), peer.local_idx())?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_timers(&self, thread_data: &mut ThreadData<T>) -> io::Result<()> {
+        for idx in self.timers.expired()? {
+            // removed peers simply drop out of the wheel
+            let Some(peer) = self.peers.load().by_index.get(&idx).cloned() else {
+                continue;
+            };
+            let (action, next) =
+                peer.update_timers(self.name.as_ref(), &mut thread_data.dst_buf, Instant::now());
+            self.take_action(&peer, action);
+            self.timers.schedule(next, idx)?;
         }
+        self.conns.reclaim();
 
         Ok(())
     }
 
-    fn handle_tun(&self, thread_data: &mut ThreadData) -> io::Result<()> {
-        let src_buf = &mut thread_data.src_buf[..];
-        while let Ok(nbytes) = self.iface.recv(src_buf) {
-            let (src, dst) = match etherparse::Ipv4HeaderSlice::from_slice(&src_buf[..nbytes]) {
+    fn handle_tun(&self, thread_data: &mut ThreadData<T>) -> io::Result<bool> {
+        let ThreadData {
+            src_buf,
+            dst_buf,
+            seg_buf,
+            out,
+            ..
+        } = thread_data;
+        let queue = self.iface.queue(out.queue);
+        for _ in 0..BUDGET {
+            let (nbytes, hdr) = match queue.recv_with_hdr(src_buf) {
+                Ok(read) => read,
+                Err(err) => {
+                    self.flush(out);
+                    // anything but running out of packets, e.g. the tun was deleted, is reported
+                    return match err.kind() {
+                        io::ErrorKind::WouldBlock => Ok(false),
+                        _ => Err(err),
+                    };
+                }
+            };
+            let packet = &mut src_buf[..nbytes];
+            let (src, dst) = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
                 Ok(iph) => {
                     let src = iph.source_addr();
                     let dst = iph.destination_addr();
@@ -169,110 +733,515 @@ impl Device {
             };
             tracing::trace!("Got Ipv4 packet of size: {nbytes}, {src} -> {dst}, from tun0");
 
-            let Some(peer) = self.peers_by_ip.get(dst.into()) else {
+            let Some(peer) = self.peers.load().by_ip.get(dst.into()).cloned() else {
                 tracing::debug!("no peer for this ip: {dst}");
                 continue;
             };
-            let action = peer.encapsulate(&src_buf[..nbytes], &mut thread_data.dst_buf);
-            self.take_action(peer, action);
+            match hdr.gso_type {
+                VIRTIO_NET_HDR_GSO_TCPV4 => {
+                    split_tso(&hdr, packet, seg_buf, |segment| {
+                        let action = peer.encapsulate(segment, dst_buf);
+                        self.queue_action(&peer, action, out);
+                    });
+                }
+                0 => {
+                    finish_checksum(&hdr, packet);
+                    let action = peer.encapsulate(packet, dst_buf);
+                    self.queue_action(&peer, action, out);
+                }
+                gso_type => tracing::debug!("unexpected gso type {gso_type} from tun"),
+            }
         }
+        self.flush(out);
 
-        Ok(())
+        Ok(true)
     }
 
-    fn handle_udp(&self, sock: &UdpSocket, thread_data: &mut ThreadData) -> io::Result<()> {
-        let src_buf = &mut thread_data.src_buf[..];
-        while let Ok((nbytes, peer_addr)) = sock.recv_from(&mut src_buf[..]) {
-            let SocketAddr::V4(peer_addr) = peer_addr else {
-                continue;
+    fn handle_udp(&self, sock: &T, thread_data: &mut ThreadData<T>) -> io::Result<bool> {
+        let ThreadData {
+            dst_buf, recv, out, ..
+        } = thread_data;
+        let mut budget = BUDGET;
+        while budget > 0 {
+            let Ok(n) = recv.recv(sock) else {
+                return Ok(false);
             };
-            let Ok(packet) = Packet::parse_from(&src_buf[..nbytes]) else {
-                continue;
-            };
-            let peer = match packet {
-                Packet::Empty => continue,
-                Packet::HandshakeInit(ref msg) => {
-                    self.peers_by_name.get(msg.sender_name.as_slice())
-                }
-                Packet::HandshakeResponse(ref msg) => {
-                    self.peers_by_index.get(msg.sender_idx as usize)
-                }
-                Packet::Data(ref msg) => self.peers_by_index.get(msg.sender_idx as usize),
-            };
-            let Some(peer) = peer else {
-                tracing::debug!("no peer found for incoming packet");
-                continue;
-            };
-
-            let (endpoint_changed, conn) = peer.set_endpoint(peer_addr);
-            if let Some(conn) = conn {
-                self.poll.delete(conn.as_ref()).expect("epoll delete");
-                drop(conn);
-            }
-            if endpoint_changed && self.use_connected_peer {
-                match peer.connect_endpoint(self.listen_port, self.fwmark) {
-                    Ok(conn) => {
-                        self.poll
-                            .register_read(
-                                Token::Sock(SockID::ConnectedPeer(peer.local_idx())),
-                                &*conn,
-                            )
-                            .expect("epoll add");
-                    }
-                    Err(err) => {
-                        tracing::error!("error connecting to peer: {:?}", err);
-                    }
+            for i in 0..n {
+                let (data, Some(peer_addr)) = recv.get(i) else {
+                    continue;
+                };
+                // datagrams coalesced by GRO
+                for datagram in data.chunks(recv.segment_size(i).max(1)) {
+                    self.handle_datagram(datagram, peer_addr, dst_buf, out);
                 }
             }
+            self.flush(out);
+            budget = budget.saturating_sub(n.max(1));
+        }
+
+        Ok(true)
+    }
+
+    fn handle_datagram(
+        &self,
+        datagram: &[u8],
+        peer_addr: SocketAddrV4,
+        dst_buf: &mut [u8],
+        out: &mut Outgoing<T>,
+    ) {
+        let Ok(packet) = Packet::parse_from(datagram) else {
+            return;
+        };
+        let Some(peer) = self.peer_for_packet(&packet) else {
+            return;
+        };
+
+        self.update_endpoint(&peer, peer_addr);
+
+        let action = peer.handle_incoming_packet(packet, dst_buf);
+        self.queue_action(&peer, action, out);
+    }
 
-            let action = peer.handle_incoming_packet(packet, &mut thread_data.dst_buf);
-            self.take_action(peer, action);
+    fn peer_for_packet(&self, packet: &Packet) -> Option<Arc<Peer<T>>> {
+        let peers = self.peers.load();
+        let peer = match packet {
+            Packet::Empty => return None,
+            Packet::HandshakeInit(msg) => peers.by_name.get(msg.sender_name.as_slice()),
+            Packet::HandshakeResponse(msg) => peers.by_index.get(&msg.sender_idx),
+            Packet::Data(msg) => peers.by_index.get(&msg.sender_idx),
+            Packet::Disconnect(msg) => peers.by_index.get(&msg.sender_idx),
+        };
+        if peer.is_none() {
+            tracing::debug!("no peer found for incoming packet");
         }
 
-        Ok(())
+        peer.cloned()
+    }
+
+    fn update_endpoint(&self, peer: &Peer<T>, addr: SocketAddrV4) {
+        if self.roam(peer, addr) {
+            self.endpoints_changed();
+        }
+    }
+
+    fn roam(&self, peer: &Peer<T>, addr: SocketAddrV4) -> bool {
+        let token = Token::Sock(SockID::ConnectedPeer(peer.local_idx()));
+        self.conns.roam(&self.poll, &self.udp[0], peer, addr, token)
     }
 
     fn handle_connected_peer(
         &self,
-        sock: &UdpSocket,
-        peer: &Peer,
-        thread_data: &mut ThreadData,
-    ) -> io::Result<()> {
-        let src_buf = &mut thread_data.src_buf[..];
-        while let Ok(nbytes) = sock.recv(&mut src_buf[..]) {
-            let Ok(packet) = Packet::parse_from(&src_buf[..nbytes]) else {
-                continue;
+        sock: &T,
+        peer: &Arc<Peer<T>>,
+        thread_data: &mut ThreadData<T>,
+    ) -> io::Result<bool> {
+        let ThreadData {
+            dst_buf, recv, out, ..
+        } = thread_data;
+        let mut budget = BUDGET;
+        while budget > 0 {
+            let Ok(n) = recv.recv(sock) else {
+                return Ok(false);
             };
+            for i in 0..n {
+                let data = recv.get(i).0;
+                for datagram in data.chunks(recv.segment_size(i).max(1)) {
+                    let Ok(packet) = Packet::parse_from(datagram) else {
+                        continue;
+                    };
 
-            let action = peer.handle_incoming_packet(packet, &mut thread_data.dst_buf);
-            self.take_action(peer, action);
+                    let action = peer.handle_incoming_packet(packet, dst_buf);
+                    self.queue_action(peer, action, out);
+                }
+            }
+            self.flush(out);
+            budget = budget.saturating_sub(n.max(1));
         }
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Like `take_action`, but writes are queued in `out`: network writes are sent when the
+    /// batch is full, with offloads tun writes of a TCP flow are merged into super-packets.
+    fn queue_action(&self, peer: &Arc<Peer<T>>, action: Action<'_>, out: &mut Outgoing<T>) {
+        match action {
+            Action::WriteToNetwork(data) if peer.is_backlogged() => self.backlog(peer, data),
+            Action::WriteToNetwork(data) => {
+                if let Some(dest) = endpoint_dest(peer) {
+                    out.udp.push(data, dest);
+                    out.peers.push(Arc::clone(peer));
+                }
+                if out.udp.is_full() {
+                    self.flush_udp(out);
+                }
+            }
+            Action::WriteToTunn(data, src_addr) if self.offload => {
+                if !peer.is_allowed_ip(src_addr) || out.tun.push(data) {
+                    return;
+                }
+                self.flush_tun(out);
+                if !out.tun.push(data) {
+                    let _ = self.iface.queue(out.queue).send(data);
+                }
+            }
+            action => self.take_action(peer, action),
+        }
     }
 
-    fn take_action(&self, peer: &Peer, action: Action<'_>) {
+    fn flush(&self, out: &mut Outgoing<T>) {
+        self.flush_udp(out);
+        self.flush_tun(out);
+    }
+
+    fn flush_udp(&self, out: &mut Outgoing<T>) {
+        let Outgoing { udp, peers, .. } = out;
+        let sock = self.listener(out.queue);
+        let result = udp.flush_with(sock, |i, data| self.backlog(&peers[i], data));
+        match result {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                tracing::trace!("dropped outgoing packets: {:?}", err);
+            }
+            _ => (),
+        }
+        peers.clear();
+    }
+
+    fn flush_tun(&self, out: &mut Outgoing<T>) {
+        let queue = self.iface.queue(out.queue);
+        out.tun.flush(|hdr, packet| {
+            let _ = queue.send_with_hdr(hdr, packet);
+        });
+    }
+
+    fn take_action(&self, peer: &Arc<Peer<T>>, action: Action<'_>) {
         match action {
             Action::WriteToTunn(data, src_addr) => {
                 if peer.is_allowed_ip(src_addr) {
-                    let _ = self.iface.send(data);
+                    let _ = self.iface.queue(0).send(data);
                 }
             }
-            Action::WriteToNetwork(data) => {
-                let _ = self.send_over_udp(peer, data);
-            }
+            Action::WriteToNetwork(data) if peer.is_backlogged() => self.backlog(peer, data),
+            Action::WriteToNetwork(data) => match self.send_over_udp(peer, data) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.backlog(peer, data),
+                _ => (),
+            },
             Action::None => (),
         }
     }
 
-    fn send_over_udp(&self, peer: &Peer, data: &[u8]) -> io::Result<usize> {
+    /// Queues a packet that did not fit in the socket buffer, the first one of a peer enables
+    /// `EPOLLOUT` on its socket
+    fn backlog(&self, peer: &Arc<Peer<T>>, data: &[u8]) {
+        if !peer.push_backlog(data) {
+            return;
+        }
+        // the socket interest only changes with this lock held, so that a drain switching it
+        // off cannot overtake a push switching it on
+        let mut backlogged = self.backlogged.lock();
+        backlogged.push(Arc::clone(peer));
+        self.has_backlog.store(true, Ordering::Release);
+        self.watch_writable(peer, true);
+    }
+
+    /// Sends backlogged packets until the sockets are full again, `EPOLLOUT` is disabled on
+    /// sockets without backlog
+    fn drain_backlogs(&self) {
+        if !self.has_backlog.load(Ordering::Acquire) {
+            return;
+        }
+        let mut backlogged = self.backlogged.lock();
+        let mut listening = false;
+        backlogged.retain(|peer| {
+            if peer.drain_backlog(|data| self.send_over_udp(peer, data)) {
+                if peer.endpoint().conn.is_some() {
+                    self.watch_writable(peer, false);
+                }
+                return false;
+            }
+            listening |= peer.endpoint().conn.is_none();
+            true
+        });
+        if !listening {
+            let token = Token::Sock(SockID::Disconnected);
+            let _ = self.polls[0].watch_writable(token, &self.udp[0], false);
+        }
+        self.has_backlog
+            .store(!backlogged.is_empty(), Ordering::Release);
+    }
+
+    fn watch_writable(&self, peer: &Peer<T>, writable: bool) {
+        let endpoint = peer.endpoint();
+        let result = match endpoint.conn {
+            Some(ref conn) => {
+                let token = Token::Sock(SockID::ConnectedPeer(peer.local_idx()));
+                self.poll.watch_writable(token, conn.as_ref(), writable)
+            }
+            // backlogs without a connected socket go out on the first listening socket. Not
+            // registered with io_uring, there only the timers drain it, e.g. if connecting failed.
+            None if self.backend == Backend::Uring => return,
+            None => {
+                let token = Token::Sock(SockID::Disconnected);
+                self.polls[0].watch_writable(token, &self.udp[0], writable)
+            }
+        };
+        if let Err(err) = result {
+            tracing::debug!("failed to watch socket: {:?}", err);
+        }
+    }
+
+    fn send_over_udp(&self, peer: &Peer<T>, data: &[u8]) -> io::Result<usize> {
         let endpoint = peer.endpoint();
         if let Some(ref conn) = endpoint.conn {
             conn.send(data)
         } else if let Some(ref addr) = endpoint.addr {
-            self.udp.send_to(data, addr)
+            self.udp[0].send_to(data, *addr)
         } else {
             Ok(0)
         }
     }
 }
+
+/// Slots of a ring for tun reads, each with a read in flight
+#[cfg(feature = "io-uring")]
+const URING_TUN_SLOTS: usize = 32;
+/// Slots of a ring for receives on the listening socket
+#[cfg(feature = "io-uring")]
+const URING_UDP_SLOTS: usize = 32;
+
+#[cfg(feature = "io-uring")]
+impl<T: Transport, I: TunnelInterface> Device<T, I> {
+    /// Like `event_loop`, but with a ring that keeps reads of tun queue `i` and receives on the
+    /// listening socket in flight. The resulting send or tun write is linked with the next read
+    /// of the same slot. Connected sockets and timers are still handled through the shared poll.
+    pub fn uring_event_loop(&self, i: usize) {
+        tracing::trace!("io_uring event loop, thread={i}");
+        if let Err(err) = self.run_ring(i) {
+            tracing::error!("io_uring event loop {i} failed: {:?}", err);
+        }
+        tracing::trace!("event loop exited, thread={i}");
+    }
+
+    fn run_ring(&self, i: usize) -> io::Result<()> {
+        let mut ring = Ring::new(URING_TUN_SLOTS + URING_UDP_SLOTS, BUF_SIZE, DST_BUF_SIZE)?;
+        let mut t = self.thread_data(i);
+        let mut shared_events = Events::with_capacity(MAX_EVENTS);
+        let mut ready = VecDeque::new();
+        let tun = self.iface.queue(i).as_fd().as_raw_fd();
+        let udp = self.listener(i);
+        let shared = self.poll.as_fd().as_raw_fd();
+        let waker = self.waker.as_fd().as_raw_fd();
+
+        for slot in 0..URING_TUN_SLOTS {
+            let read = ring.read(tun, slot);
+            ring.push(&[read])?;
+        }
+        for slot in URING_TUN_SLOTS..URING_TUN_SLOTS + URING_UDP_SLOTS {
+            let recv = ring.recv(udp, slot);
+            ring.push(&[recv])?;
+        }
+        ring.push(&[ring.poll(shared)])?;
+        ring.push(&[ring.poll(waker)])?;
+        // peer of the send in flight from each slot, failed sends count as dropped
+        let mut senders = vec![None; URING_TUN_SLOTS + URING_UDP_SLOTS];
+
+        loop {
+            if ready.is_empty() {
+                ring.wait()?;
+            } else {
+                ring.submit()?;
+            }
+            while let Some(completion) = ring.completion() {
+                match completion {
+                    Completion::Read { slot, res: Ok(n) } => {
+                        senders[slot] = self.ring_tun(&mut ring, tun, udp, slot, n)?;
+                    }
+                    Completion::Read {
+                        slot,
+                        res: Err(err),
+                    } => {
+                        if !would_block(&err) {
+                            tracing::error!("tun error {:?}", err);
+                            continue;
+                        }
+                        let poll = ring.poll_slot(tun, slot);
+                        let read = ring.read(tun, slot);
+                        ring.push(&[poll, read])?;
+                    }
+                    Completion::Recv {
+                        slot,
+                        res: Ok((n, addr)),
+                    } => senders[slot] = self.ring_udp(&mut ring, tun, udp, slot, n, addr)?,
+                    Completion::Recv {
+                        slot,
+                        res: Err(err),
+                    } => {
+                        if !would_block(&err) {
+                            tracing::error!("udp error {:?}", err);
+                            continue;
+                        }
+                        let poll = ring.poll_slot(udp.as_fd().as_raw_fd(), slot);
+                        let recv = ring.recv(udp, slot);
+                        ring.push(&[poll, recv])?;
+                    }
+                    Completion::Sent { slot, res } => {
+                        let peer = senders[slot].take();
+                        if let (Err(err), Some(peer)) = (res, peer) {
+                            peer.count_dropped();
+                            tracing::trace!("dropped outgoing packet: {:?}", err);
+                        }
+                    }
+                    Completion::Written(Err(err)) => {
+                        tracing::trace!("dropped incoming packet: {:?}", err);
+                    }
+                    Completion::Written(Ok(_)) => (),
+                    Completion::Readable(fd) if fd == waker => {
+                        if self.is_shutdown.load(Ordering::Acquire) {
+                            return Ok(());
+                        }
+                        ring.push(&[ring.poll(waker)])?;
+                    }
+                    Completion::Readable(_) => {
+                        self.take_shared(&mut shared_events, &mut ready);
+                        ring.push(&[ring.poll(shared)])?;
+                    }
+                }
+            }
+            self.run_ready(&mut ready, &mut t);
+        }
+    }
+
+    /// Handles a packet read from the tun into `slot` and reads the next one into it, returns
+    /// the peer the packet is sent to
+    fn ring_tun(
+        &self,
+        ring: &mut Ring<T>,
+        tun: RawFd,
+        udp: &T,
+        slot: usize,
+        nbytes: usize,
+    ) -> io::Result<Option<Arc<Peer<T>>>> {
+        let read = ring.read(tun, slot);
+        let buf = ring.buf(slot);
+        let base = buf.as_ptr();
+        let (src, dst) = buf.split_at_mut(BUF_SIZE);
+        let packet = &src[..nbytes];
+
+        let Ok(iph) = etherparse::Ipv4HeaderSlice::from_slice(packet) else {
+            return ring.push(&[read]).map(|_| None);
+        };
+        let dst_addr = iph.destination_addr();
+        let Some(peer) = self.peers.load().by_ip.get(dst_addr.into()).cloned() else {
+            tracing::debug!("no peer for this ip: {dst_addr}");
+            return ring.push(&[read]).map(|_| None);
+        };
+        let Action::WriteToNetwork(data) = peer.encapsulate(packet, dst) else {
+            return ring.push(&[read]).map(|_| None);
+        };
+        // behind packets waiting for the socket, as in `queue_action`
+        if peer.is_backlogged() {
+            self.backlog(&peer, data);
+            return ring.push(&[read]).map(|_| None);
+        }
+        let range = range_in(base, data);
+
+        match endpoint_dest(&peer) {
+            Some(dest) => {
+                let send = ring.send(udp, slot, range, dest);
+                ring.push(&[send, read])?;
+                Ok(Some(peer))
+            }
+            None => ring.push(&[read]).map(|_| None),
+        }
+    }
+
+    /// Handles a datagram received into `slot` and receives the next one into it, returns the
+    /// peer a reply is sent to
+    fn ring_udp(
+        &self,
+        ring: &mut Ring<T>,
+        tun: RawFd,
+        udp: &T,
+        slot: usize,
+        nbytes: usize,
+        peer_addr: SocketAddrV4,
+    ) -> io::Result<Option<Arc<Peer<T>>>> {
+        let recv = ring.recv(udp, slot);
+        let buf = ring.buf(slot);
+        let base = buf.as_ptr();
+        let (src, dst) = buf.split_at_mut(BUF_SIZE);
+
+        let Ok(packet) = Packet::parse_from(&src[..nbytes]) else {
+            return ring.push(&[recv]).map(|_| None);
+        };
+        let Some(peer) = self.peer_for_packet(&packet) else {
+            return ring.push(&[recv]).map(|_| None);
+        };
+        self.update_endpoint(&peer, peer_addr);
+
+        let (out, sender) = match peer.handle_incoming_packet(packet, dst) {
+            Action::WriteToTunn(data, src_addr) if peer.is_allowed_ip(src_addr) => {
+                let range = range_in(base, data);
+                (Some(ring.write(tun, slot, range)), None)
+            }
+            Action::WriteToNetwork(data) if peer.is_backlogged() => {
+                self.backlog(&peer, data);
+                (None, None)
+            }
+            Action::WriteToNetwork(data) => {
+                let range = range_in(base, data);
+                match endpoint_dest(&peer) {
+                    Some(dest) => (Some(ring.send(udp, slot, range, dest)), Some(peer)),
+                    None => (None, None),
+                }
+            }
+            _ => (None, None),
+        };
+
+        match out {
+            Some(out) => ring.push(&[out, recv])?,
+            None => ring.push(&[recv])?,
+        }
+        Ok(sender)
+    }
+}
+
+#[cfg(feature = "io-uring")]
+fn would_block(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+fn queue_ready(ready: &mut VecDeque<Token<SockID>>, token: Token<SockID>) {
+    if !ready.contains(&token) {
+        ready.push_back(token);
+    }
+}
+
+/// Where packets for `peer` go, its connected socket if there is one
+fn endpoint_dest<T>(peer: &Peer<T>) -> Option<Dest<T>> {
+    let endpoint = peer.endpoint();
+    match endpoint.conn {
+        Some(ref conn) => Some(Dest::Conn(Arc::clone(conn))),
+        None => endpoint.addr.map(Dest::Addr),
+    }
+}
+
+fn same_allowed_ips<T>(peer: &Peer<T>, allowed_ips: &[(Ipv4Addr, u8)]) -> bool {
+    let mut current: Vec<_> = peer
+        .allowed_ips()
+        .iter()
+        .map(|(_, ip, cidr)| (ip, cidr))
+        .collect();
+    let mut wanted: Vec<_> = allowed_ips
+        .iter()
+        .filter_map(|&(ip, cidr)| ip_network::Ipv4Network::new_truncate(ip, cidr).ok())
+        .map(|net| (net.network_address().into(), net.netmask()))
+        .collect();
+    current.sort();
+    wanted.sort();
+    wanted.dedup();
+
+    current == wanted
+}
@@ -0,0 +1,392 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io;
+use std::net::SocketAddrV4;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::transport::Transport;
+
+/// How long the delay thread waits without holding on to the transport, so that it notices
+/// when the last handle is dropped
+const DELAY_IDLE: Duration = Duration::from_millis(100);
+
+/// Network conditions applied by `Impaired`. Probabilities are per datagram, from 0 to 1.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Impairment {
+    /// Sent datagrams that are dropped
+    pub loss: f64,
+    /// Sent datagrams that go out twice
+    pub duplicate: f64,
+    /// Delay of every sent datagram
+    pub latency: Duration,
+    /// Up to this much is added to `latency`, uniformly distributed. Datagrams may overtake
+    /// each other if it is more than their spacing.
+    pub jitter: Duration,
+    /// Sent datagrams that skip the delay and so overtake the ones still delayed, like netem's
+    /// reorder. Only has an effect with `latency`.
+    pub reorder: f64,
+    /// Received datagrams upon which the NAT in front of their sender picks a new source port
+    /// for it, see `Impaired::rebind`
+    pub rebind: f64,
+}
+
+/// A transport that sends and receives through `T` under an `Impairment`, with every random
+/// decision taken from a generator seeded by `seed`. Given the same packets in the same order,
+/// the same ones are lost, duplicated, delayed and rebound.
+///
+/// Remote addresses are seen through a simulated NAT: datagrams from a remote appear to come
+/// from its current external port, and datagrams to that port are passed on to the remote.
+/// Rebinding gives a remote a new external port, after which anything sent to its old ones is
+/// lost, so the device only reaches the remote again once it follows it to the new port.
+///
+/// Clones share the transport and its impairment, e.g. to change it while a device uses it.
+pub struct Impaired<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    inner: T,
+    state: Mutex<State>,
+    /// Signalled when a delayed datagram is queued
+    queued: Condvar,
+}
+
+struct State {
+    impairment: Impairment,
+    rng: Rng,
+    /// Delayed datagrams by due time, then in the order they were sent
+    delayed: BinaryHeap<Reverse<Delayed>>,
+    next_seq: u64,
+    nat: Nat,
+    delay_thread: bool,
+}
+
+/// Due time, sequence number, destination and datagram
+type Delayed = (Instant, u64, SocketAddrV4, Vec<u8>);
+
+#[derive(Default)]
+struct Nat {
+    /// Current external address of each remote
+    external: HashMap<SocketAddrV4, SocketAddrV4>,
+    /// Remote behind each current external address
+    internal: HashMap<SocketAddrV4, SocketAddrV4>,
+    /// External addresses that have been rebound away from
+    stale: HashSet<SocketAddrV4>,
+}
+
+impl<T: Transport> Impaired<T> {
+    pub fn new(inner: T, impairment: Impairment, seed: u64) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                state: Mutex::new(State {
+                    impairment,
+                    rng: Rng(seed),
+                    delayed: BinaryHeap::new(),
+                    next_seq: 0,
+                    nat: Nat::default(),
+                    delay_thread: false,
+                }),
+                queued: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.shared.inner
+    }
+
+    pub fn set_impairment(&self, impairment: Impairment) {
+        self.shared.state.lock().impairment = impairment;
+    }
+
+    /// Gives every remote seen so far a new external port, as if their NATs timed out
+    pub fn rebind(&self) {
+        let mut state = self.shared.state.lock();
+        let remotes: Vec<_> = state.nat.external.keys().copied().collect();
+        for remote in remotes {
+            state.nat.rebind(remote);
+        }
+    }
+
+    /// Queues `buf` to be sent after `delay`, starting the delay thread if it is not running
+    fn send_later(&self, state: &mut State, delay: Duration, to: SocketAddrV4, buf: &[u8]) {
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state
+            .delayed
+            .push(Reverse((Instant::now() + delay, seq, to, buf.to_vec())));
+        self.shared.queued.notify_one();
+
+        if !state.delay_thread {
+            let shared = Arc::downgrade(&self.shared);
+            let spawned = std::thread::Builder::new()
+                .name("wontun-impair".to_string())
+                .spawn(move || send_delayed(shared));
+            match spawned {
+                Ok(_) => state.delay_thread = true,
+                Err(err) => tracing::error!("failed to spawn delay thread: {:?}", err),
+            }
+        }
+    }
+}
+
+/// Sends delayed datagrams when they are due, until the transport is dropped
+fn send_delayed<T: Transport>(shared: Weak<Shared<T>>) {
+    while let Some(shared) = shared.upgrade() {
+        let mut state = shared.state.lock();
+        let now = Instant::now();
+        let due = match state.delayed.peek() {
+            Some(Reverse((due, ..))) if *due <= now => state.delayed.pop(),
+            Some(Reverse((due, ..))) => {
+                let due = (*due).min(now + DELAY_IDLE);
+                shared.queued.wait_until(&mut state, due);
+                None
+            }
+            None => {
+                shared.queued.wait_for(&mut state, DELAY_IDLE);
+                None
+            }
+        };
+        drop(state);
+
+        if let Some(Reverse((_, _, to, buf))) = due {
+            if let Err(err) = shared.inner.send_to(&buf, to) {
+                tracing::trace!("dropped delayed datagram: {:?}", err);
+            }
+        }
+    }
+}
+
+impl<T> Clone for Impaired<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T: Transport> Transport for Impaired<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        let mut state = self.shared.state.lock();
+        let Some(to) = state.nat.internal_addr(addr) else {
+            // sent to a port the NAT no longer maps
+            return Ok(buf.len());
+        };
+
+        let impairment = state.impairment.clone();
+        let copies = if state.rng.chance(impairment.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            if state.rng.chance(impairment.loss) {
+                continue;
+            }
+            let mut delay = impairment.latency;
+            if !impairment.jitter.is_zero() {
+                delay += impairment.jitter.mul_f64(state.rng.next_f64());
+            }
+            if state.rng.chance(impairment.reorder) {
+                delay = Duration::ZERO;
+            }
+
+            if delay.is_zero() {
+                // sent inline, like a socket with buffer space: a full one drops the datagram
+                if let Err(err) = self.shared.inner.send_to(buf, to) {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        return Err(err);
+                    }
+                }
+            } else {
+                self.send_later(&mut state, delay, to, buf);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let (n, from) = self.shared.inner.recv_from(buf)?;
+
+        let mut state = self.shared.state.lock();
+        let rebind = state.impairment.rebind;
+        if state.rng.chance(rebind) {
+            state.nat.rebind(from);
+        }
+
+        Ok((n, state.nat.external_addr(from)))
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.inner.send(buf)
+    }
+}
+
+impl<T: AsFd> AsFd for Impaired<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.shared.inner.as_fd()
+    }
+}
+
+impl Nat {
+    fn external_addr(&mut self, remote: SocketAddrV4) -> SocketAddrV4 {
+        if let Some(&external) = self.external.get(&remote) {
+            return external;
+        }
+        self.external.insert(remote, remote);
+        self.internal.insert(remote, remote);
+
+        remote
+    }
+
+    /// The remote behind `addr`, `None` if that is a port it has been rebound away from
+    fn internal_addr(&self, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        match self.internal.get(&addr) {
+            Some(&remote) => Some(remote),
+            None if self.stale.contains(&addr) => None,
+            // not seen yet, e.g. the configured endpoint before the first reply
+            None => Some(addr),
+        }
+    }
+
+    fn rebind(&mut self, remote: SocketAddrV4) {
+        let old = self.external_addr(remote);
+        let mut port = old.port();
+        let new = loop {
+            port = port.checked_add(1).unwrap_or(1024);
+            let new = SocketAddrV4::new(*remote.ip(), port);
+            if new != remote && !self.internal.contains_key(&new) && !self.stale.contains(&new) {
+                break new;
+            }
+        };
+
+        self.internal.remove(&old);
+        self.stale.insert(old);
+        self.internal.insert(new, remote);
+        self.external.insert(remote, new);
+    }
+}
+
+/// splitmix64, small and good enough to draw impairments from
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        // nothing is drawn for impairments that are off, so enabling one does not shift the
+        // decisions of the others
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemNetwork;
+
+    fn received(b: &impl Transport) -> Vec<(Vec<u8>, SocketAddrV4)> {
+        let mut buf = [0u8; 16];
+        let mut received = Vec::new();
+        while let Ok((n, from)) = b.recv_from(&mut buf) {
+            received.push((buf[..n].to_vec(), from));
+        }
+        received
+    }
+
+    /// Like `received`, but waits up to a few seconds for the first datagram
+    fn received_eventually(b: &impl Transport) -> Vec<(Vec<u8>, SocketAddrV4)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let received = received(b);
+            if !received.is_empty() || Instant::now() >= deadline {
+                return received;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_seeded_impairment() {
+        let impairment = Impairment {
+            loss: 0.3,
+            duplicate: 0.2,
+            ..Impairment::default()
+        };
+        let run = |seed| {
+            let network = MemNetwork::new();
+            let a = network.bind("10.0.0.1:1".parse().unwrap()).unwrap();
+            let b = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+            let a = Impaired::new(a, impairment.clone(), seed);
+            for i in 0..100u8 {
+                a.send_to(&[i], b.local_addr()).unwrap();
+            }
+            received(&b)
+        };
+
+        let first = run(7);
+        assert_eq!(first, run(7));
+        assert_ne!(first, run(8));
+        assert!(first.len() > 50 && first.len() < 100, "{}", first.len());
+    }
+
+    #[test]
+    fn test_latency_and_rebind() {
+        let network = MemNetwork::new();
+        let a = network.bind("10.0.0.1:1".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+        let latency = Duration::from_millis(50);
+        let impairment = Impairment {
+            latency,
+            ..Impairment::default()
+        };
+        let b = Impaired::new(b, impairment, 1);
+
+        a.send_to(b"hi", b.inner().local_addr()).unwrap();
+        let (_, from) = b.recv_from(&mut [0u8; 16]).unwrap();
+        assert_eq!(from, a.local_addr());
+
+        // replies take `latency` to arrive
+        let start = Instant::now();
+        b.send_to(b"reply", from).unwrap();
+        let early = received(&a);
+        let arrived = if early.is_empty() {
+            received_eventually(&a)
+        } else {
+            // only if this thread was held up for that long
+            assert!(start.elapsed() >= latency);
+            early
+        };
+        assert_eq!(arrived, [(b"reply".to_vec(), b.inner().local_addr())]);
+        assert!(start.elapsed() >= latency);
+
+        b.set_impairment(Impairment::default());
+        b.rebind();
+        a.send_to(b"again", b.inner().local_addr()).unwrap();
+        let (_, rebound) = b.recv_from(&mut [0u8; 16]).unwrap();
+        assert_eq!(rebound.ip(), a.local_addr().ip());
+        assert_ne!(rebound, from);
+
+        // only the new port reaches a
+        b.send_to(b"old", from).unwrap();
+        b.send_to(b"new", rebound).unwrap();
+        assert_eq!(received(&a), [(b"new".to_vec(), b.inner().local_addr())]);
+    }
+}
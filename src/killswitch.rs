@@ -0,0 +1,339 @@
+// https://wiki.nftables.org/wiki-nftables/index.php/Portal:DeveloperDocs/nftables_internals
+use std::io;
+use std::net::SocketAddrV4;
+
+use nix::libc;
+
+use crate::netlink::{self, Message, Netlink, NLM_F_APPEND, NLM_F_CREATE};
+
+// linux/netfilter/nfnetlink.h, linux/netfilter/nf_tables.h, linux/netfilter.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_NEWSET: u16 = 9;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_SET_KEY_TYPE: u16 = 4;
+const NFTA_SET_KEY_LEN: u16 = 5;
+const NFTA_SET_ID: u16 = 10;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_LOOKUP_SET: u16 = 1;
+const NFTA_LOOKUP_SREG: u16 = 2;
+const NFTA_LOOKUP_SET_ID: u16 = 4;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFT_META_MARK: u32 = 3;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+// 4-byte registers, consecutive ones hold a concatenation
+const NFT_REG32_00: u32 = 8;
+const NFT_REG32_01: u32 = 9;
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const IFNAMSIZ: usize = 16;
+
+const TABLE: &str = "wontun";
+const CHAIN: &str = "output";
+const SET: &str = "endpoints";
+const SET_ID: u32 = 1;
+/// `ipv4_addr . inet_service`, each field padded to 4 bytes
+const SET_KEY_TYPE: u32 = 7 << 6 | 13;
+const SET_KEY_LEN: u32 = 8;
+
+/// nftables rules that drop all outgoing traffic but that through the tunnel and the device's
+/// own packets to its peers, so nothing leaks while the tunnel is down:
+///
+/// ```text
+/// table inet wontun {
+///     chain output {
+///         type filter hook output priority 0; policy drop;
+///         oifname "lo" accept
+///         oifname "<tun>" accept
+///         meta mark <fwmark> ip daddr . udp dport @endpoints accept
+///     }
+/// }
+/// ```
+///
+/// The rules stay in place until `remove`, through handshake timeouts and peers going away.
+/// The `endpoints` set follows the peers, see `set_endpoints`.
+pub struct KillSwitch {
+    netlink: Netlink,
+    tun_name: String,
+    fwmark: u32,
+    installed: bool,
+}
+
+impl KillSwitch {
+    pub fn new(tun_name: &str, fwmark: u32) -> io::Result<Self> {
+        Ok(Self {
+            netlink: Netlink::netfilter()?,
+            tun_name: tun_name.to_string(),
+            fwmark,
+            installed: false,
+        })
+    }
+
+    /// Installs the rules for the peers at `endpoints`, atomically replacing any installed
+    /// before, e.g. by a run that did not shut down cleanly
+    pub fn install(&mut self, endpoints: &[SocketAddrV4]) -> io::Result<()> {
+        let mut msgs = vec![
+            // deleting fails unless the table exists, so it is created first
+            table(NFT_MSG_NEWTABLE, NLM_F_CREATE),
+            table(NFT_MSG_DELTABLE, 0),
+            table(NFT_MSG_NEWTABLE, NLM_F_CREATE),
+            chain(),
+            set(),
+            rule(|r| r.meta(NFT_META_OIFNAME).cmp_eq(&ifname("lo")).accept()),
+            rule(|r| {
+                r.meta(NFT_META_OIFNAME)
+                    .cmp_eq(&ifname(&self.tun_name))
+                    .accept()
+            }),
+            rule(|r| {
+                r.meta(NFT_META_MARK)
+                    .cmp_eq(&self.fwmark.to_ne_bytes())
+                    .meta(NFT_META_NFPROTO)
+                    .cmp_eq(&[NFPROTO_IPV4])
+                    .meta(NFT_META_L4PROTO)
+                    .cmp_eq(&[libc::IPPROTO_UDP as u8])
+                    .payload(NFT_REG32_00, NFT_PAYLOAD_NETWORK_HEADER, 16, 4)
+                    .payload(NFT_REG32_01, NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2)
+                    .lookup(NFT_REG32_00)
+                    .accept()
+            }),
+        ];
+        msgs.extend(add_elements(endpoints));
+        self.netlink.request_all(batch(msgs))?;
+        self.installed = true;
+        tracing::info!("kill switch on, allowing {} endpoints", endpoints.len());
+
+        Ok(())
+    }
+
+    /// Replaces the endpoints the device may send to, e.g. when a peer roams
+    pub fn set_endpoints(&mut self, endpoints: &[SocketAddrV4]) -> io::Result<()> {
+        if !self.installed {
+            return Ok(());
+        }
+        // without elements, the set is flushed
+        let mut msgs = vec![set_elements(NFT_MSG_DELSETELEM, 0)];
+        msgs.extend(add_elements(endpoints));
+        self.netlink.request_all(batch(msgs))?;
+        tracing::debug!("kill switch allowing {} endpoints", endpoints.len());
+
+        Ok(())
+    }
+
+    /// Removes the rules, on a clean shutdown. Does nothing unless they were installed.
+    pub fn remove(&mut self) -> io::Result<()> {
+        if !self.installed {
+            return Ok(());
+        }
+        let msgs = batch(vec![table(NFT_MSG_DELTABLE, 0)]);
+        netlink::ignore(self.netlink.request_all(msgs), libc::ENOENT)?;
+        self.installed = false;
+
+        Ok(())
+    }
+}
+
+/// `msgs` as one nf_tables transaction, applied entirely or not at all
+fn batch(msgs: Vec<Message>) -> Vec<Message> {
+    let subsys = NFNL_SUBSYS_NFTABLES.to_be_bytes();
+    let nfgenmsg = [libc::AF_UNSPEC as u8, 0, subsys[0], subsys[1]];
+    let mut batch = vec![Message::unacked(NFNL_MSG_BATCH_BEGIN, 0).fixed(&nfgenmsg)];
+    batch.extend(msgs);
+    batch.push(Message::unacked(NFNL_MSG_BATCH_END, 0).fixed(&nfgenmsg));
+    batch
+}
+
+fn nft_message(ty: u16, flags: u16) -> Message {
+    Message::new(NFNL_SUBSYS_NFTABLES << 8 | ty, flags).fixed(&[NFPROTO_INET, 0, 0, 0])
+}
+
+fn table(ty: u16, flags: u16) -> Message {
+    nft_message(ty, flags).attr(NFTA_TABLE_NAME, &cstr(TABLE))
+}
+
+fn chain() -> Message {
+    nft_message(NFT_MSG_NEWCHAIN, NLM_F_CREATE)
+        .attr(NFTA_CHAIN_TABLE, &cstr(TABLE))
+        .attr(NFTA_CHAIN_NAME, &cstr(CHAIN))
+        .nested(NFTA_CHAIN_HOOK, |hook| {
+            hook.attr(NFTA_HOOK_HOOKNUM, &NF_INET_LOCAL_OUT.to_be_bytes())
+                .attr(NFTA_HOOK_PRIORITY, &0u32.to_be_bytes())
+        })
+        .attr(NFTA_CHAIN_POLICY, &NF_DROP.to_be_bytes())
+        .attr(NFTA_CHAIN_TYPE, &cstr("filter"))
+}
+
+fn set() -> Message {
+    nft_message(NFT_MSG_NEWSET, NLM_F_CREATE)
+        .attr(NFTA_SET_TABLE, &cstr(TABLE))
+        .attr(NFTA_SET_NAME, &cstr(SET))
+        .attr(NFTA_SET_KEY_TYPE, &SET_KEY_TYPE.to_be_bytes())
+        .attr(NFTA_SET_KEY_LEN, &SET_KEY_LEN.to_be_bytes())
+        .attr(NFTA_SET_ID, &SET_ID.to_be_bytes())
+}
+
+fn set_elements(ty: u16, flags: u16) -> Message {
+    nft_message(ty, flags)
+        .attr(NFTA_SET_ELEM_LIST_TABLE, &cstr(TABLE))
+        .attr(NFTA_SET_ELEM_LIST_SET, &cstr(SET))
+}
+
+/// Adds `endpoints` to the set, nothing if there are none (an empty list is invalid)
+fn add_elements(endpoints: &[SocketAddrV4]) -> Option<Message> {
+    if endpoints.is_empty() {
+        return None;
+    }
+    let msg = set_elements(NFT_MSG_NEWSETELEM, NLM_F_CREATE).nested(
+        NFTA_SET_ELEM_LIST_ELEMENTS,
+        |mut list| {
+            for endpoint in endpoints {
+                list = list.nested(NFTA_LIST_ELEM, |elem| {
+                    elem.nested(NFTA_SET_ELEM_KEY, |key| {
+                        key.attr(NFTA_DATA_VALUE, &set_key(endpoint))
+                    })
+                });
+            }
+            list
+        },
+    );
+    Some(msg)
+}
+
+/// `endpoint` as the rule concatenates it: the address, then the port padded to 4 bytes
+fn set_key(endpoint: &SocketAddrV4) -> [u8; SET_KEY_LEN as usize] {
+    let mut key = [0u8; SET_KEY_LEN as usize];
+    key[..4].copy_from_slice(&endpoint.ip().octets());
+    key[4..6].copy_from_slice(&endpoint.port().to_be_bytes());
+    key
+}
+
+/// A rule appended to the chain, made of the expressions added by `exprs`
+fn rule(exprs: impl FnOnce(Message) -> Message) -> Message {
+    nft_message(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND)
+        .attr(NFTA_RULE_TABLE, &cstr(TABLE))
+        .attr(NFTA_RULE_CHAIN, &cstr(CHAIN))
+        .nested(NFTA_RULE_EXPRESSIONS, exprs)
+}
+
+/// The expressions of a rule, evaluated in order on a single register
+trait Exprs: Sized {
+    fn expr(self, name: &str, data: impl FnOnce(Message) -> Message) -> Message;
+
+    /// Loads the packet's metadata `key`
+    fn meta(self, key: u32) -> Message {
+        self.expr("meta", |data| {
+            data.attr(NFTA_META_KEY, &key.to_be_bytes())
+                .attr(NFTA_META_DREG, &NFT_REG_1.to_be_bytes())
+        })
+    }
+
+    /// Loads `len` bytes at `offset` into the header `base`
+    fn payload(self, dreg: u32, base: u32, offset: u32, len: u32) -> Message {
+        self.expr("payload", |data| {
+            data.attr(NFTA_PAYLOAD_DREG, &dreg.to_be_bytes())
+                .attr(NFTA_PAYLOAD_BASE, &base.to_be_bytes())
+                .attr(NFTA_PAYLOAD_OFFSET, &offset.to_be_bytes())
+                .attr(NFTA_PAYLOAD_LEN, &len.to_be_bytes())
+        })
+    }
+
+    /// Ends the rule unless the value loaded from `sreg` on is in the set
+    fn lookup(self, sreg: u32) -> Message {
+        self.expr("lookup", |data| {
+            data.attr(NFTA_LOOKUP_SET, &cstr(SET))
+                .attr(NFTA_LOOKUP_SET_ID, &SET_ID.to_be_bytes())
+                .attr(NFTA_LOOKUP_SREG, &sreg.to_be_bytes())
+        })
+    }
+
+    /// Ends the rule unless the loaded value is `value`
+    fn cmp_eq(self, value: &[u8]) -> Message {
+        self.expr("cmp", |data| {
+            data.attr(NFTA_CMP_SREG, &NFT_REG_1.to_be_bytes())
+                .attr(NFTA_CMP_OP, &NFT_CMP_EQ.to_be_bytes())
+                .nested(NFTA_CMP_DATA, |cmp| cmp.attr(NFTA_DATA_VALUE, value))
+        })
+    }
+
+    fn accept(self) -> Message {
+        self.expr("immediate", |data| {
+            data.attr(NFTA_IMMEDIATE_DREG, &NFT_REG_VERDICT.to_be_bytes())
+                .nested(NFTA_IMMEDIATE_DATA, |imm| {
+                    imm.nested(NFTA_DATA_VERDICT, |verdict| {
+                        verdict.attr(NFTA_VERDICT_CODE, &NF_ACCEPT.to_be_bytes())
+                    })
+                })
+        })
+    }
+}
+
+impl Exprs for Message {
+    fn expr(self, name: &str, data: impl FnOnce(Message) -> Message) -> Message {
+        self.nested(NFTA_LIST_ELEM, |elem| {
+            elem.attr(NFTA_EXPR_NAME, &cstr(name))
+                .nested(NFTA_EXPR_DATA, data)
+        })
+    }
+}
+
+fn cstr(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// `name` as the `oifname` register holds it, padded with nul bytes
+fn ifname(name: &str) -> [u8; IFNAMSIZ] {
+    let mut padded = [0u8; IFNAMSIZ];
+    let len = name.len().min(IFNAMSIZ - 1);
+    padded[..len].copy_from_slice(&name.as_bytes()[..len]);
+    padded
+}
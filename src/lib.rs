@@ -1,11 +1,34 @@
 mod allowed_ip;
 mod conf;
+mod conn;
 mod dev;
+mod impair;
+mod killswitch;
+mod netlink;
+mod offload;
 mod packet;
 mod peer;
 mod poll;
+mod sync;
+mod timer;
+mod transport;
+mod tun;
+mod tunnel;
 mod udp;
+#[cfg(feature = "io-uring")]
+mod uring;
 
-pub use conf::Conf;
-pub use dev::{Device, DeviceConfig};
+pub use conf::{Conf, ConfSource, Diagnostic, Severity};
+pub use dev::{Backend, Device, DeviceConfig};
+pub use impair::{Impaired, Impairment};
+pub use killswitch::KillSwitch;
+pub use netlink::Link;
+pub use offload::VirtioNetHdr;
+pub use packet::{HandshakeResponse, Packet};
 pub use peer::{Action, Endpoint, Peer, PeerName};
+pub use transport::{MemNetwork, MemTransport, Transport};
+pub use tun::{Tun, TunConfig, TunQueue};
+pub use tunnel::{MemTun, MemTunQueue, TunnelInterface, TunnelQueue};
+pub use udp::{Dest, RecvBatch, SendBatch, BATCH_SIZE};
+#[cfg(feature = "io-uring")]
+pub use uring::{Completion, Ring};
@@ -0,0 +1,469 @@
+// https://docs.kernel.org/userspace-api/netlink/intro.html
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+
+use nix::libc;
+use nix::net::if_::if_nametoindex;
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
+
+// linux/netlink.h
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+pub(crate) const NLM_F_CREATE: u16 = 0x400;
+pub(crate) const NLM_F_APPEND: u16 = 0x800;
+const NLA_F_NESTED: u16 = 0x8000;
+
+// linux/rtnetlink.h, linux/if_addr.h, linux/if_link.h, linux/fib_rules.h
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFLA_MTU: u16 = 4;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+const RT_TABLE_UNSPEC: u8 = 0;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
+
+/// A netlink socket talking to the kernel, one request or batch at a time
+pub(crate) struct Netlink {
+    sock: File,
+    seq: u32,
+}
+
+/// A netlink message under construction: header, fixed part and attributes
+pub(crate) struct Message {
+    buf: Vec<u8>,
+}
+
+impl Netlink {
+    pub fn route() -> io::Result<Self> {
+        Self::open(SockProtocol::NetlinkRoute)
+    }
+
+    pub fn netfilter() -> io::Result<Self> {
+        Self::open(SockProtocol::NetlinkNetFilter)
+    }
+
+    fn open(protocol: SockProtocol) -> io::Result<Self> {
+        let sock = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            protocol,
+        )?;
+
+        Ok(Self {
+            sock: sock.into(),
+            seq: 0,
+        })
+    }
+
+    /// Sends `msg` and waits for the kernel to acknowledge it
+    pub fn request(&mut self, msg: Message) -> io::Result<()> {
+        self.request_all(vec![msg])
+    }
+
+    /// Sends `msgs` in one datagram, as nfnetlink wants its batches, and waits for the kernel to
+    /// acknowledge the last one. Fails with the first error reported for any of them.
+    pub fn request_all(&mut self, msgs: Vec<Message>) -> io::Result<()> {
+        let first = self.seq.wrapping_add(1);
+        let mut last_acked = None;
+        let mut datagram = Vec::new();
+        for mut msg in msgs {
+            self.seq = self.seq.wrapping_add(1);
+            let len = msg.buf.len() as u32;
+            msg.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+            msg.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+            if u16::from_ne_bytes([msg.buf[6], msg.buf[7]]) & NLM_F_ACK != 0 {
+                last_acked = Some(self.seq);
+            }
+            datagram.extend_from_slice(&msg.buf);
+        }
+        self.sock.write_all(&datagram)?;
+        let Some(last) = last_acked else {
+            return Ok(());
+        };
+
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = self.sock.read(&mut buf)?;
+            let mut replies = &buf[..n];
+            while replies.len() >= NLMSG_HDR_LEN {
+                let u32_at = |i: usize| u32::from_ne_bytes(replies[i..i + 4].try_into().unwrap());
+                let len = (u32_at(0) as usize).clamp(NLMSG_HDR_LEN, replies.len());
+                let ty = u16::from_ne_bytes([replies[4], replies[5]]);
+                let seq = u32_at(8);
+                // replies left over from an earlier request that failed are skipped
+                let ours = seq.wrapping_sub(first) <= self.seq.wrapping_sub(first);
+                if ty == NLMSG_ERROR && ours && len >= NLMSG_HDR_LEN + 4 {
+                    let err = u32_at(NLMSG_HDR_LEN) as i32;
+                    if err != 0 {
+                        return Err(io::Error::from_raw_os_error(-err));
+                    }
+                    if seq == last {
+                        return Ok(());
+                    }
+                }
+                replies = &replies[align(len).min(replies.len())..];
+            }
+        }
+    }
+}
+
+impl Message {
+    /// A request the kernel acknowledges
+    pub fn new(ty: u16, flags: u16) -> Self {
+        Self::unacked(ty, flags | NLM_F_ACK)
+    }
+
+    /// A request the kernel only answers on errors, e.g. the start and end of a batch
+    pub fn unacked(ty: u16, flags: u16) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDR_LEN];
+        buf[4..6].copy_from_slice(&ty.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST).to_ne_bytes());
+        Self { buf }
+    }
+
+    /// Appends the fixed part following the header (`ifaddrmsg`, `rtmsg`, ...)
+    pub fn fixed(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    pub fn attr(mut self, ty: u16, data: &[u8]) -> Self {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    /// Appends an attribute holding the attributes added by `f`
+    pub fn nested(mut self, ty: u16, f: impl FnOnce(Self) -> Self) -> Self {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&[0, 0]);
+        self.buf
+            .extend_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+        let mut msg = f(self);
+        let len = (msg.buf.len() - start) as u16;
+        msg.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        msg
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// `result`, with the error `errno` taken as success
+pub(crate) fn ignore(result: io::Result<()>, errno: i32) -> io::Result<()> {
+    match result {
+        Err(err) if err.raw_os_error() == Some(errno) => Ok(()),
+        result => result,
+    }
+}
+
+/// The tun interface as seen by the rest of the system: its address, MTU and the routes to the
+/// peers' allowed ips, configured over rtnetlink
+pub struct Link {
+    netlink: Netlink,
+    index: u32,
+    /// Mark of the packets sent by the device itself, which must not be routed into the tunnel
+    fwmark: Option<u32>,
+    /// Routes added by `set_routes`, routes that existed before are left alone
+    routes: BTreeSet<(Ipv4Addr, u8)>,
+    /// Whether the default route and its policy rules are installed
+    full_tunnel: bool,
+    /// `src_valid_mark` as it was before the default route was installed, put back on removal
+    src_valid_mark: Option<String>,
+}
+
+impl Link {
+    /// The link of the tun interface `name`, for a device marking its own packets with `fwmark`
+    pub fn open(name: &str, fwmark: Option<u32>) -> io::Result<Self> {
+        Ok(Self {
+            netlink: Netlink::route()?,
+            index: if_nametoindex(name)?,
+            fwmark,
+            routes: BTreeSet::new(),
+            full_tunnel: false,
+            src_valid_mark: None,
+        })
+    }
+
+    /// Assigns `address`, sets `mtu` and brings the link up. An address that is already
+    /// assigned, e.g. to an attached persistent tun, is kept.
+    pub fn configure(&mut self, address: (Ipv4Addr, u8), mtu: u16) -> io::Result<()> {
+        let (ip, prefix_len) = address;
+        let mut ifaddrmsg = [
+            libc::AF_INET as u8,
+            prefix_len,
+            0,
+            RT_SCOPE_UNIVERSE,
+            0,
+            0,
+            0,
+            0,
+        ];
+        ifaddrmsg[4..8].copy_from_slice(&self.index.to_ne_bytes());
+        let msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL)
+            .fixed(&ifaddrmsg)
+            .attr(IFA_LOCAL, &ip.octets())
+            .attr(IFA_ADDRESS, &ip.octets());
+        ignore(self.netlink.request(msg), libc::EEXIST)?;
+
+        let mut ifinfomsg = [0u8; 16];
+        ifinfomsg[0] = libc::AF_UNSPEC as u8;
+        ifinfomsg[4..8].copy_from_slice(&self.index.to_ne_bytes());
+        ifinfomsg[8..12].copy_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        ifinfomsg[12..16].copy_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        let msg = Message::new(RTM_NEWLINK, 0)
+            .fixed(&ifinfomsg)
+            .attr(IFLA_MTU, &(mtu as u32).to_ne_bytes());
+        self.netlink.request(msg)
+    }
+
+    /// Routes `allowed_ips` through the link: adds the routes that are missing and removes the
+    /// ones added before that are no longer wanted, e.g. after a conf reload.
+    ///
+    /// A default route `0.0.0.0/0` goes into a table of its own, numbered after the fwmark, which
+    /// applies to all packets but the device's own, like `wg-quick` does:
+    ///
+    /// ```text
+    /// ip route add 0.0.0.0/0 dev <tun> table <fwmark>
+    /// ip rule add not fwmark <fwmark> table <fwmark>
+    /// ip rule add table main suppress_prefixlength 0
+    /// ```
+    pub fn set_routes(&mut self, allowed_ips: &[(Ipv4Addr, u8)]) -> io::Result<()> {
+        let mut wanted = BTreeSet::new();
+        let mut full_tunnel = false;
+        for &(ip, prefix_len) in allowed_ips {
+            if prefix_len == 0 {
+                full_tunnel = true;
+                continue;
+            }
+            wanted.insert((ip, prefix_len));
+        }
+
+        if self.full_tunnel && !full_tunnel {
+            self.remove_full_tunnel()?;
+        }
+        let stale: Vec<_> = self.routes.difference(&wanted).copied().collect();
+        for route in stale {
+            // routes go away with the link, which may be gone already on shutdown
+            ignore(
+                self.route(RTM_DELROUTE, 0, route, RT_TABLE_MAIN as u32),
+                libc::ESRCH,
+            )?;
+            self.routes.remove(&route);
+        }
+        for &route in wanted.difference(&self.routes.clone()) {
+            // e.g. the prefix route of the interface address, or one set up by hand
+            let flags = NLM_F_CREATE | NLM_F_EXCL;
+            match self.route(RTM_NEWROUTE, flags, route, RT_TABLE_MAIN as u32) {
+                Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                    tracing::debug!("route to {}/{} exists already", route.0, route.1);
+                }
+                Err(err) => return Err(err),
+                Ok(()) => {
+                    self.routes.insert(route);
+                }
+            }
+        }
+        if full_tunnel && !self.full_tunnel {
+            self.add_full_tunnel()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the routes and rules added by `set_routes`, on shutdown
+    pub fn remove_routes(&mut self) -> io::Result<()> {
+        self.set_routes(&[])
+    }
+
+    fn add_full_tunnel(&mut self) -> io::Result<()> {
+        let Some(fwmark) = self.fwmark else {
+            tracing::warn!(
+                "not routing 0.0.0.0/0 without a fwmark, it would catch the tunnel itself"
+            );
+            return Ok(());
+        };
+        // replies to the device's packets arrive unmarked, and would fail the reverse path
+        // filter against the default route of the tunnel otherwise
+        match fs::read_to_string(SRC_VALID_MARK) {
+            Ok(previous) if previous.trim() == "1" => (),
+            Ok(previous) => match fs::write(SRC_VALID_MARK, "1") {
+                Ok(()) => self.src_valid_mark = Some(previous),
+                Err(err) => tracing::warn!("cannot set src_valid_mark {:?}", err),
+            },
+            Err(err) => tracing::warn!("cannot read src_valid_mark {:?}", err),
+        }
+
+        // from here on, a partial setup is undone by `remove_full_tunnel`
+        self.full_tunnel = true;
+        let flags = NLM_F_CREATE | NLM_F_EXCL;
+        let default = (Ipv4Addr::UNSPECIFIED, 0);
+        ignore(
+            self.route(RTM_NEWROUTE, flags, default, fwmark),
+            libc::EEXIST,
+        )?;
+        // rules without a priority go in front of the ones added before, so the suppressing
+        // one is looked at first
+        ignore(
+            self.netlink
+                .request(fwmark_rule(RTM_NEWRULE, flags, fwmark)),
+            libc::EEXIST,
+        )?;
+        ignore(
+            self.netlink.request(suppress_rule(RTM_NEWRULE, flags)),
+            libc::EEXIST,
+        )?;
+        tracing::info!("routing 0.0.0.0/0 through the tunnel, in table {fwmark}");
+
+        Ok(())
+    }
+
+    fn remove_full_tunnel(&mut self) -> io::Result<()> {
+        let Some(fwmark) = self.fwmark else {
+            return Ok(());
+        };
+        ignore(
+            self.netlink.request(suppress_rule(RTM_DELRULE, 0)),
+            libc::ENOENT,
+        )?;
+        ignore(
+            self.netlink.request(fwmark_rule(RTM_DELRULE, 0, fwmark)),
+            libc::ENOENT,
+        )?;
+        let default = (Ipv4Addr::UNSPECIFIED, 0);
+        ignore(self.route(RTM_DELROUTE, 0, default, fwmark), libc::ESRCH)?;
+        if let Some(previous) = self.src_valid_mark.take() {
+            if let Err(err) = fs::write(SRC_VALID_MARK, previous) {
+                tracing::warn!("cannot restore src_valid_mark {:?}", err);
+            }
+        }
+        self.full_tunnel = false;
+
+        Ok(())
+    }
+
+    fn route(
+        &mut self,
+        ty: u16,
+        flags: u16,
+        (dst, dst_len): (Ipv4Addr, u8),
+        table: u32,
+    ) -> io::Result<()> {
+        // tables past 255 only fit into the attribute
+        let rtm_table = u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC);
+        let rtmsg = [
+            libc::AF_INET as u8,
+            dst_len,
+            0,
+            0,
+            rtm_table,
+            RTPROT_BOOT,
+            RT_SCOPE_LINK,
+            RTN_UNICAST,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let msg = Message::new(ty, flags)
+            .fixed(&rtmsg)
+            .attr(RTA_TABLE, &table.to_ne_bytes())
+            .attr(RTA_DST, &dst.octets())
+            .attr(RTA_OIF, &self.index.to_ne_bytes());
+        self.netlink.request(msg)
+    }
+}
+
+/// `fib_rule_hdr` of a rule looking up a table, `flags` of the rule itself
+fn rule_hdr(flags: u32) -> [u8; 12] {
+    let mut hdr = [
+        libc::AF_INET as u8,
+        0,
+        0,
+        0,
+        RT_TABLE_UNSPEC,
+        0,
+        0,
+        FR_ACT_TO_TBL,
+        0,
+        0,
+        0,
+        0,
+    ];
+    hdr[8..12].copy_from_slice(&flags.to_ne_bytes());
+    hdr
+}
+
+/// `not fwmark <fwmark> table <fwmark>`
+fn fwmark_rule(ty: u16, flags: u16, fwmark: u32) -> Message {
+    Message::new(ty, flags)
+        .fixed(&rule_hdr(FIB_RULE_INVERT))
+        .attr(FRA_FWMARK, &fwmark.to_ne_bytes())
+        .attr(FRA_TABLE, &fwmark.to_ne_bytes())
+}
+
+/// `table main suppress_prefixlength 0`, the main table for everything but its default route
+fn suppress_rule(ty: u16, flags: u16) -> Message {
+    Message::new(ty, flags)
+        .fixed(&rule_hdr(0))
+        .attr(FRA_TABLE, &(RT_TABLE_MAIN as u32).to_ne_bytes())
+        .attr(FRA_SUPPRESS_PREFIXLEN, &0u32.to_ne_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_layout() {
+        let msg = Message::new(RTM_NEWROUTE, NLM_F_CREATE)
+            .fixed(&[1, 2, 3, 4, 5])
+            .attr(RTA_OIF, &7u32.to_ne_bytes())
+            .attr(RTA_DST, &[10, 0, 0]);
+
+        let buf = &msg.buf;
+        assert_eq!(buf.len(), NLMSG_HDR_LEN + 8 + 8 + 8);
+        assert_eq!(u16::from_ne_bytes([buf[4], buf[5]]), RTM_NEWROUTE);
+        assert_eq!(
+            u16::from_ne_bytes([buf[6], buf[7]]),
+            NLM_F_CREATE | NLM_F_REQUEST | NLM_F_ACK
+        );
+        // fixed part padded to 4 bytes, then attributes with their length before padding
+        assert_eq!(&buf[16..24], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(&buf[24..28], &[8, 0, RTA_OIF as u8, 0]);
+        assert_eq!(&buf[32..40], &[7, 0, RTA_DST as u8, 0, 10, 0, 0, 0]);
+
+        let msg = Message::new(RTM_NEWROUTE, 0).nested(3, |msg| msg.attr(1, &[1, 2, 3]));
+        let nested = (3 | NLA_F_NESTED).to_ne_bytes();
+        assert_eq!(&msg.buf[16..20], &[12, 0, nested[0], nested[1]]);
+        assert_eq!(&msg.buf[20..28], &[7, 0, 1, 0, 1, 2, 3, 0]);
+    }
+}
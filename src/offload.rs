@@ -0,0 +1,389 @@
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2050006
+// https://www.kernel.org/doc/html/latest/networking/segmentation-offloads.html
+//
+// With IFF_VNET_HDR every packet on the tun is preceded by a virtio-net header. It lets the
+// kernel hand out TCP super-packets (TSO) and packets with a partial checksum, and accept the
+// same in the other direction, which is what makes bulk transfers cheap.
+
+/// Size of the (legacy) virtio-net header, as set with TUNSETVNETHDRSZ
+pub const VNET_HDR_LEN: usize = 10;
+
+/// Largest packet (with all segments) the kernel hands out or accepts with offloads on
+pub const MAX_SUPER_PACKET: usize = 65535;
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+
+const IPPROTO_TCP: u8 = 6;
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    // legacy virtio headers are in native byte order
+    pub fn parse(src: &[u8; VNET_HDR_LEN]) -> Self {
+        let u16_at = |i: usize| u16::from_ne_bytes([src[i], src[i + 1]]);
+        Self {
+            flags: src[0],
+            gso_type: src[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        }
+    }
+
+    pub fn format(&self) -> [u8; VNET_HDR_LEN] {
+        let mut dst = [0u8; VNET_HDR_LEN];
+        dst[0] = self.flags;
+        dst[1] = self.gso_type;
+        dst[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        dst[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        dst[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        dst[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        dst
+    }
+}
+
+/// Completes a partial checksum (`VIRTIO_NET_HDR_F_NEEDS_CSUM`): the checksum field holds the
+/// pseudo header sum, the rest is summed from `csum_start` to the end of the packet.
+pub fn finish_checksum(hdr: &VirtioNetHdr, packet: &mut [u8]) {
+    if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        return;
+    }
+    let start = hdr.csum_start as usize;
+    let field = start + hdr.csum_offset as usize;
+    if field + 2 > packet.len() {
+        return;
+    }
+    let csum = !fold(sum(0, &packet[start..]));
+    packet[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+}
+
+/// Splits a TCP/IPv4 super-packet (`VIRTIO_NET_HDR_GSO_TCPV4`) into segments with at most
+/// `gso_size` bytes of payload, calling `f` with each of them in `buf`, headers and checksums
+/// fixed up as if the kernel had segmented them.
+pub fn split_tso(hdr: &VirtioNetHdr, packet: &[u8], buf: &mut [u8], mut f: impl FnMut(&[u8])) {
+    let Some(tcp) = TcpPacket::parse(packet) else {
+        return;
+    };
+    let mss = hdr.gso_size as usize;
+    if mss == 0 || tcp.hdr_len + mss > buf.len() {
+        tracing::debug!("can not split super-packet with gso size {mss}");
+        return;
+    }
+
+    let ip_id = u16::from_be_bytes([packet[4], packet[5]]);
+    let payload = &packet[tcp.hdr_len..];
+    let segments = payload.len().div_ceil(mss);
+
+    for (i, chunk) in payload.chunks(mss).enumerate() {
+        let len = tcp.hdr_len + chunk.len();
+        let seg = &mut buf[..len];
+        seg[..tcp.hdr_len].copy_from_slice(&packet[..tcp.hdr_len]);
+        seg[tcp.hdr_len..].copy_from_slice(chunk);
+
+        seg[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        seg[4..6].copy_from_slice(&ip_id.wrapping_add(i as u16).to_be_bytes());
+        let seq = tcp.seq.wrapping_add((i * mss) as u32);
+        seg[tcp.ip_hdr_len + 4..tcp.ip_hdr_len + 8].copy_from_slice(&seq.to_be_bytes());
+        if i + 1 < segments {
+            seg[tcp.ip_hdr_len + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+
+        set_ip_checksum(seg, tcp.ip_hdr_len);
+        let csum_field = tcp.ip_hdr_len + 16..tcp.ip_hdr_len + 18;
+        seg[csum_field.clone()].fill(0);
+        let csum = !fold(sum(
+            tcp_pseudo_header_sum(seg, tcp.ip_hdr_len),
+            &seg[tcp.ip_hdr_len..],
+        ));
+        seg[csum_field].copy_from_slice(&csum.to_be_bytes());
+
+        f(seg);
+    }
+}
+
+/// Merges consecutive in-order segments of one TCP flow into a single super-packet, so that a
+/// burst received over the network takes one tun write instead of one per segment.
+pub struct TcpCoalescer {
+    buf: Vec<u8>,
+    tcp: Option<TcpPacket>,
+    mss: usize,
+    count: usize,
+    next_seq: u32,
+    closed: bool,
+}
+
+impl Default for TcpCoalescer {
+    fn default() -> Self {
+        Self {
+            buf: Vec::with_capacity(MAX_SUPER_PACKET),
+            tcp: None,
+            mss: 0,
+            count: 0,
+            next_seq: 0,
+            closed: false,
+        }
+    }
+}
+
+impl TcpCoalescer {
+    /// Adds `packet` to the pending super-packet, or starts a new one if there is none. Returns
+    /// false if it can not be merged, in which case the caller has to `flush` and try again, or
+    /// write it out as is if it still does not fit.
+    pub fn push(&mut self, packet: &[u8]) -> bool {
+        let Some(tcp) = TcpPacket::parse(packet).filter(|tcp| tcp.is_coalescable(packet)) else {
+            return false;
+        };
+        let payload_len = packet.len() - tcp.hdr_len;
+
+        let Some(ref pending) = self.tcp else {
+            self.buf.clear();
+            self.buf.extend_from_slice(packet);
+            self.tcp = Some(tcp);
+            self.mss = payload_len;
+            self.count = 1;
+            self.next_seq = tcp.seq.wrapping_add(payload_len as u32);
+            self.closed = packet[tcp.ip_hdr_len + 13] & TCP_FLAG_PSH != 0;
+            return true;
+        };
+
+        let can_merge = !self.closed
+            && tcp.seq == self.next_seq
+            && payload_len <= self.mss
+            && self.buf.len() + payload_len <= MAX_SUPER_PACKET
+            && tcp.hdr_len == pending.hdr_len
+            && same_headers(&self.buf[..pending.hdr_len], &packet[..tcp.hdr_len], &tcp);
+        if !can_merge {
+            return false;
+        }
+
+        self.buf.extend_from_slice(&packet[tcp.hdr_len..]);
+        self.count += 1;
+        self.next_seq = tcp.seq.wrapping_add(payload_len as u32);
+        // a short segment or a push ends the burst
+        if payload_len < self.mss || packet[tcp.ip_hdr_len + 13] & TCP_FLAG_PSH != 0 {
+            self.buf[pending.ip_hdr_len + 13] |= packet[tcp.ip_hdr_len + 13] & TCP_FLAG_PSH;
+            self.closed = true;
+        }
+
+        true
+    }
+
+    /// Hands the pending packet with its virtio-net header to `f`, if there is one
+    pub fn flush(&mut self, f: impl FnOnce(&VirtioNetHdr, &[u8])) {
+        let Some(tcp) = self.tcp.take() else {
+            return;
+        };
+        if self.count == 1 {
+            // untouched, checksums are still valid
+            return f(&VirtioNetHdr::default(), &self.buf);
+        }
+
+        let len = self.buf.len();
+        self.buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        set_ip_checksum(&mut self.buf, tcp.ip_hdr_len);
+        // the kernel finishes the checksum of each segment from the pseudo header sum
+        let csum = fold(tcp_pseudo_header_sum(&self.buf, tcp.ip_hdr_len));
+        self.buf[tcp.ip_hdr_len + 16..tcp.ip_hdr_len + 18].copy_from_slice(&csum.to_be_bytes());
+
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: tcp.hdr_len as u16,
+            gso_size: self.mss as u16,
+            csum_start: tcp.ip_hdr_len as u16,
+            csum_offset: 16,
+        };
+        f(&hdr, &self.buf);
+    }
+}
+
+/// Offsets of a TCP/IPv4 packet
+#[derive(Debug, Copy, Clone)]
+struct TcpPacket {
+    ip_hdr_len: usize,
+    hdr_len: usize,
+    seq: u32,
+}
+
+impl TcpPacket {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != IPPROTO_TCP {
+            return None;
+        }
+        let ip_hdr_len = (packet[0] & 0x0f) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if ip_hdr_len < 20 || total_len != packet.len() || packet.len() < ip_hdr_len + 20 {
+            return None;
+        }
+        let hdr_len = ip_hdr_len + (packet[ip_hdr_len + 12] >> 4) as usize * 4;
+        if hdr_len < ip_hdr_len + 20 || hdr_len > packet.len() {
+            return None;
+        }
+        let seq = u32::from_be_bytes(packet[ip_hdr_len + 4..ip_hdr_len + 8].try_into().unwrap());
+
+        Some(Self {
+            ip_hdr_len,
+            hdr_len,
+            seq,
+        })
+    }
+
+    /// Plain data segments only: no ip fragments or options, nothing but ACK (and PSH) set
+    fn is_coalescable(&self, packet: &[u8]) -> bool {
+        let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+        let flags = packet[self.ip_hdr_len + 13];
+        self.ip_hdr_len == 20
+            && !fragmented
+            && flags & !TCP_FLAG_PSH == TCP_FLAG_ACK
+            && packet.len() > self.hdr_len
+    }
+}
+
+/// Whether two segments belong together: same addresses, ports, ack, window and tcp options,
+/// everything else (length, id, checksums, seq, PSH) may differ
+fn same_headers(a: &[u8], b: &[u8], tcp: &TcpPacket) -> bool {
+    let ip = tcp.ip_hdr_len;
+    a[0..2] == b[0..2]
+        && a[8..10] == b[8..10]
+        && a[12..20] == b[12..20]
+        && a[ip..ip + 4] == b[ip..ip + 4]
+        && a[ip + 8..ip + 12] == b[ip + 8..ip + 12]
+        && a[ip + 14..ip + 16] == b[ip + 14..ip + 16]
+        && a[ip + 20..] == b[ip + 20..]
+}
+
+fn set_ip_checksum(packet: &mut [u8], ip_hdr_len: usize) {
+    packet[10..12].fill(0);
+    let csum = !fold(sum(0, &packet[..ip_hdr_len]));
+    packet[10..12].copy_from_slice(&csum.to_be_bytes());
+}
+
+fn tcp_pseudo_header_sum(packet: &[u8], ip_hdr_len: usize) -> u32 {
+    let tcp_len = (packet.len() - ip_hdr_len) as u16;
+    let mut acc = sum(0, &packet[12..20]);
+    acc += IPPROTO_TCP as u32;
+    acc += tcp_len as u32;
+    acc
+}
+
+/// Ones' complement sum of big endian 16 bit words, without the final fold
+fn sum(mut acc: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        acc = acc.wrapping_add(u16::from_be_bytes([word[0], word[1]]) as u32);
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_segment(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            0x45,
+            0,
+            0,
+            0,
+            0x12,
+            0x34,
+            0x40,
+            0,
+            64,
+            IPPROTO_TCP,
+            0,
+            0,
+            10,
+            0,
+            0,
+            1,
+            10,
+            0,
+            0,
+            2,
+        ];
+        packet.extend_from_slice(&[0x1f, 0x90, 0xc3, 0x50]);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 1, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        set_ip_checksum(&mut packet, 20);
+        let csum = !fold(sum(tcp_pseudo_header_sum(&packet, 20), &packet[20..]));
+        packet[36..38].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_coalesce_and_split() {
+        let payload: Vec<u8> = (0..250u32).map(|i| i as u8).collect();
+        let segments: Vec<Vec<u8>> = payload
+            .chunks(100)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let flags = if i == 2 {
+                    TCP_FLAG_ACK | TCP_FLAG_PSH
+                } else {
+                    TCP_FLAG_ACK
+                };
+                tcp_segment(1000 + i as u32 * 100, flags, chunk)
+            })
+            .collect();
+
+        let mut gro = TcpCoalescer::default();
+        for segment in &segments {
+            assert!(gro.push(segment));
+        }
+        // nothing goes after a push
+        assert!(!gro.push(&tcp_segment(1250, TCP_FLAG_ACK, &[0; 10])));
+
+        let mut super_packet = None;
+        gro.flush(|hdr, packet| super_packet = Some((*hdr, packet.to_vec())));
+        let (hdr, mut packet) = super_packet.unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 100);
+        assert_eq!(packet.len(), 40 + 250);
+
+        // which is what the kernel would do with it, and splitting gives back the originals
+        let mut buf = [0u8; 1500];
+        let mut split = vec![];
+        split_tso(&hdr, &packet, &mut buf, |seg| split.push(seg.to_vec()));
+        for seg in &mut split {
+            seg[4..6].copy_from_slice(&[0x12, 0x34]);
+            set_ip_checksum(seg, 20);
+        }
+        assert_eq!(split, segments);
+
+        // and a partial checksum finishes into the full one
+        finish_checksum(&hdr, &mut packet);
+        assert_eq!(
+            fold(sum(tcp_pseudo_header_sum(&packet, 20), &packet[20..])),
+            0xffff
+        );
+    }
+}
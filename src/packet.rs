@@ -5,6 +5,7 @@ pub enum Packet<'a> {
     HandshakeInit(HandshakeInit<'a>),
     HandshakeResponse(HandshakeResponse),
     Data(PacketData<'a>),
+    Disconnect(Disconnect),
     Empty,
 }
 
@@ -26,13 +27,23 @@ pub struct PacketData<'a> {
     pub data: &'a [u8],
 }
 
+/// Sent to connected peers on shutdown, so they drop the session instead of sending into the void
+#[derive(Debug)]
+pub struct Disconnect {
+    pub sender_idx: u32,
+}
+
 const HANDSHAKE_INIT: u8 = 1;
 const HANDSHAKE_RESPONSE: u8 = 2;
 const PACKET_DATA: u8 = 3;
+const DISCONNECT: u8 = 4;
 
 const HANDSHAKE_INIT_SIZE: usize = PeerName::max_len() + 5;
 const HANDSHAKE_RESPONSE_SIZE: usize = 9;
-const DATA_MIN_SIZE: usize = 5;
+/// Bytes a data packet adds to the IP packet it carries
+pub(crate) const DATA_HEADER_LEN: usize = 5;
+const DATA_MIN_SIZE: usize = DATA_HEADER_LEN;
+const DISCONNECT_SIZE: usize = 5;
 
 #[derive(Debug, Copy, Clone)]
 pub enum PackeParseError {
@@ -71,6 +82,11 @@ impl<'a> Packet<'a> {
                     data: &src[5..],
                 }))
             }
+            (DISCONNECT, DISCONNECT_SIZE) => {
+                let sender_idx = u32::from_le_bytes(src[1..5].try_into().unwrap());
+
+                Ok(Packet::Disconnect(Disconnect { sender_idx }))
+            }
             _ => Err(PackeParseError::ProtocolErr),
         }
     }
@@ -103,7 +119,7 @@ impl HandshakeResponse {
 impl<'a> PacketData<'a> {
     pub fn format(&self, dst: &mut [u8]) -> usize {
         let n = self.data.len();
-        let len = n + 5;
+        let len = n + DATA_HEADER_LEN;
         assert!(dst.len() >= len);
 
         dst[0] = PACKET_DATA;
@@ -113,3 +129,14 @@ impl<'a> PacketData<'a> {
         len
     }
 }
+
+impl Disconnect {
+    pub fn format(&self, dst: &mut [u8]) -> usize {
+        assert!(dst.len() >= DISCONNECT_SIZE);
+
+        dst[0] = DISCONNECT;
+        dst[1..5].copy_from_slice(&self.sender_idx.to_le_bytes());
+
+        DISCONNECT_SIZE
+    }
+}
@@ -1,28 +1,103 @@
 use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
 
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use parking_lot::{RwLock, RwLockReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use thiserror::Error;
 
 use crate::allowed_ip::AllowedIps;
-use crate::packet::{HandshakeInit, HandshakeResponse, Packet, PacketData};
+use crate::packet::{Disconnect, HandshakeInit, HandshakeResponse, Packet, PacketData};
+use crate::sync::{self, ArcSwap, Guard};
+use crate::timer::TICK;
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct PeerName<T = [u8; PEER_NAME_MAX_LEN]>(T);
 
-pub struct Peer {
+pub struct Peer<T = UdpSocket> {
     local_idx: u32,
-    handshake_state: RwLock<HandshakeState>,
-    endpoint: RwLock<Endpoint>,
-    allowed_ips: AllowedIps<()>,
+    handshake_state: sync::RwLock<HandshakeState>,
+    /// `remote_idx | SESSION_CONNECTED` while `handshake_state` is `Connected`, 0 otherwise.
+    /// Only written under the `handshake_state` write lock, read without it by the data path.
+    session: sync::AtomicU64,
+    endpoint: ArcSwap<Endpoint<T>>,
+    /// Serializes endpoint updates, readers just load `endpoint`
+    endpoint_update: sync::Mutex<()>,
+    allowed_ips: RwLock<AllowedIps<()>>,
+    timers: Timers,
+    backlog: Mutex<VecDeque<Vec<u8>>>,
+    backlogged: AtomicBool,
+    dropped: AtomicU64,
 }
 
-#[derive(Default)]
-pub struct Endpoint {
+/// Packets queued per peer while its socket is full, further ones are dropped
+pub const BACKLOG_LEN: usize = 1024;
+
+/// Resend a handshake init if no response arrived in time
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Send an empty data packet if nothing was sent on a session for this long
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Drop a session (and handshake again) if nothing was received for this long
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SESSION_CONNECTED: u64 = 1 << 32;
+
+struct Timers {
+    handshake_sent: AtomicInstant,
+    last_sent: AtomicInstant,
+    last_received: AtomicInstant,
+}
+
+/// An `Instant` updated without locking, as nanoseconds since `base`
+struct AtomicInstant {
+    base: Instant,
+    nanos: AtomicU64,
+}
+
+impl AtomicInstant {
+    fn new(now: Instant) -> Self {
+        Self {
+            base: now,
+            nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn load(&self) -> Instant {
+        self.base + Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, t: Instant) {
+        let nanos = t.saturating_duration_since(self.base).as_nanos() as u64;
+        self.nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Like `store`, but skipped while less than a timer `TICK` ahead of the stored value, so
+    /// that a stream of packets writes the shared cache line at most once per tick
+    fn touch(&self, t: Instant) {
+        let nanos = t.saturating_duration_since(self.base).as_nanos() as u64;
+        if nanos.saturating_sub(self.nanos.load(Ordering::Relaxed)) >= TICK.as_nanos() as u64 {
+            self.nanos.store(nanos, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Where a peer is reached, with its connected transport if there is one
+pub struct Endpoint<T = UdpSocket> {
     pub addr: Option<SocketAddrV4>,
-    pub conn: Option<Arc<UdpSocket>>,
+    pub conn: Option<Arc<T>>,
+}
+
+impl<T> Default for Endpoint<T> {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            conn: None,
+        }
+    }
 }
 
 pub enum Action<'a> {
@@ -39,32 +114,101 @@ enum HandshakeState {
     Connected { remote_idx: u32 },
 }
 
-impl Default for Peer {
+impl<T> Default for Peer<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Peer {
+impl<T> Peer<T> {
     pub fn new() -> Self {
+        let now = Instant::now();
         Self {
             local_idx: 0,
-            handshake_state: RwLock::new(HandshakeState::None),
-            endpoint: RwLock::new(Endpoint::default()),
-            allowed_ips: AllowedIps::new(),
+            handshake_state: sync::RwLock::new(HandshakeState::None),
+            session: sync::AtomicU64::new(0),
+            endpoint: ArcSwap::from_pointee(Endpoint::default()),
+            endpoint_update: sync::Mutex::new(()),
+            allowed_ips: RwLock::new(AllowedIps::new()),
+            timers: Timers {
+                handshake_sent: AtomicInstant::new(now),
+                last_sent: AtomicInstant::new(now),
+                last_received: AtomicInstant::new(now),
+            },
+            backlog: Mutex::new(VecDeque::new()),
+            backlogged: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
         }
     }
 
-    pub fn allowed_ips(&self) -> &AllowedIps<()> {
-        &self.allowed_ips
+    pub fn allowed_ips(&self) -> RwLockReadGuard<'_, AllowedIps<()>> {
+        self.allowed_ips.read()
     }
 
     pub fn add_allowed_ip(&mut self, addr: Ipv4Addr, cidr: u8) {
-        self.allowed_ips.insert(addr.into(), cidr, ());
+        self.allowed_ips.get_mut().insert(addr.into(), cidr, ());
+    }
+
+    /// Replaces allowed ips of an already shared peer, e.g. on conf reload
+    pub fn set_allowed_ips(&self, allowed_ips: &[(Ipv4Addr, u8)]) {
+        let mut ips = AllowedIps::new();
+        ips.extend(
+            allowed_ips
+                .iter()
+                .map(|&(addr, cidr)| (addr.into(), cidr, ())),
+        );
+        *self.allowed_ips.write() = ips;
     }
 
     pub fn is_allowed_ip(&self, addr: Ipv4Addr) -> bool {
-        self.allowed_ips.get(addr.into()).is_some()
+        self.allowed_ips.read().get(addr.into()).is_some()
+    }
+
+    /// Whether packets are waiting for the socket to become writable, new ones have to queue up
+    /// behind them with `push_backlog` to keep their order
+    pub fn is_backlogged(&self) -> bool {
+        self.backlogged.load(Ordering::Acquire)
+    }
+
+    /// Queues a packet that could not be sent because the socket buffer is full, or drops it if
+    /// `BACKLOG_LEN` packets are queued already. Returns `true` if the backlog was empty.
+    pub fn push_backlog(&self, data: &[u8]) -> bool {
+        let mut backlog = self.backlog.lock();
+        if backlog.len() >= BACKLOG_LEN {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::trace!("backlog full, dropping packet");
+            return false;
+        }
+        backlog.push_back(data.to_vec());
+        self.backlogged.store(true, Ordering::Release);
+
+        backlog.len() == 1
+    }
+
+    /// Sends backlogged packets in order until `send` would block. Returns `true` once the
+    /// backlog is empty.
+    pub fn drain_backlog(&self, mut send: impl FnMut(&[u8]) -> io::Result<usize>) -> bool {
+        let mut backlog = self.backlog.lock();
+        while let Some(data) = backlog.front() {
+            match send(data) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return false,
+                // other errors drop the packet, like for packets sent right away
+                _ => backlog.pop_front(),
+            };
+        }
+        self.backlogged.store(false, Ordering::Release);
+
+        true
+    }
+
+    /// Packets dropped because the backlog was full, or sending them failed with io_uring
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Counts a packet that could not be sent
+    pub fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn local_idx(&self) -> u32 {
@@ -75,36 +219,44 @@ impl Peer {
         self.local_idx = idx;
     }
 
-    pub fn endpoint(&self) -> RwLockReadGuard<Endpoint> {
-        self.endpoint.read()
+    pub fn endpoint(&self) -> Guard<Arc<Endpoint<T>>> {
+        self.endpoint.load()
     }
 
-    pub fn set_endpoint(&self, addr: SocketAddrV4) -> (bool, Option<Arc<UdpSocket>>) {
-        let endpoint = self.endpoint.read();
-        if endpoint.addr == Some(addr) {
+    pub fn set_endpoint(&self, addr: SocketAddrV4) -> (bool, Option<Arc<T>>) {
+        if self.endpoint.load().addr == Some(addr) {
             return (false, None);
         }
-        drop(endpoint);
-
-        let mut endpoint = self.endpoint.write();
-        endpoint.addr = Some(addr);
 
-        (true, endpoint.conn.take())
+        let mut conn = None;
+        let changed = self.update_endpoint(|endpoint| {
+            if endpoint.addr == Some(addr) {
+                return None;
+            }
+            conn.clone_from(&endpoint.conn);
+            Some(Endpoint {
+                addr: Some(addr),
+                conn: None,
+            })
+        });
+
+        (changed, conn)
     }
 
-    pub fn connect_endpoint(&self, port: u16, fwmark: Option<u32>) -> io::Result<Arc<UdpSocket>> {
-        let mut endpoint = self.endpoint.write();
-        let addr = endpoint.addr.unwrap();
-
-        assert!(endpoint.conn.is_none());
-
-        let conn = crate::udp::new_socket(port, fwmark)?;
-        conn.connect(addr)?;
-        let conn = Arc::new(conn);
-
-        endpoint.conn = Some(Arc::clone(&conn));
+    /// Replaces the endpoint with what `update` returns for the current one, if anything. Updates
+    /// run one at a time, so `update` can set up and tear down the sockets of the endpoint
+    /// without racing another update. Returns whether the endpoint was replaced.
+    pub fn update_endpoint(
+        &self,
+        update: impl FnOnce(&Endpoint<T>) -> Option<Endpoint<T>>,
+    ) -> bool {
+        let _update = self.endpoint_update.lock();
+        let Some(endpoint) = update(&self.endpoint.load()) else {
+            return false;
+        };
+        self.endpoint.store(Arc::new(endpoint));
 
-        Ok(conn)
+        true
     }
 
     pub fn send_handshake<'a>(
@@ -115,29 +267,132 @@ impl Peer {
         let mut state = self.handshake_state.write();
         let endpoint_set = { self.endpoint().addr.is_some() };
         if HandshakeState::None == *state && endpoint_set {
-            let packet = HandshakeInit {
-                sender_name,
-                assigned_idx: self.local_idx(),
-            };
-            let n = packet.format(dst);
-
-            *state = HandshakeState::HandshakeSent;
+            self.set_state(&mut state, HandshakeState::HandshakeSent);
 
             tracing::debug!("sending handshake");
-            Action::WriteToNetwork(&dst[..n])
+            self.format_handshake(sender_name, dst)
         } else {
             Action::None
         }
     }
 
+    /// `remote_idx` of the current session, without taking the `handshake_state` lock
+    fn session(&self) -> Option<u32> {
+        let session = self.session.load(Ordering::Acquire);
+        (session & SESSION_CONNECTED != 0).then_some(session as u32)
+    }
+
+    fn set_state(&self, state: &mut HandshakeState, new: HandshakeState) {
+        *state = new;
+        let session = match new {
+            HandshakeState::Connected { remote_idx } => remote_idx as u64 | SESSION_CONNECTED,
+            _ => 0,
+        };
+        self.session.store(session, Ordering::Release);
+    }
+
+    fn format_handshake<'a>(&self, sender_name: PeerName<&[u8]>, dst: &'a mut [u8]) -> Action<'a> {
+        let packet = HandshakeInit {
+            sender_name,
+            assigned_idx: self.local_idx(),
+        };
+        let n = packet.format(dst);
+
+        self.timers.handshake_sent.store(Instant::now());
+
+        Action::WriteToNetwork(&dst[..n])
+    }
+
+    /// Handshake retry, keepalive and session expiry, called when the deadline returned by the
+    /// previous call has passed (or right after the peer is added). Returns what to send now and
+    /// when to be called again.
+    pub fn update_timers<'a>(
+        &self,
+        sender_name: PeerName<&[u8]>,
+        dst: &'a mut [u8],
+        now: Instant,
+    ) -> (Action<'a>, Instant) {
+        let mut state = self.handshake_state.write();
+        let timers = &self.timers;
+        match *state {
+            HandshakeState::None => {
+                drop(state);
+
+                // down after a disconnect or a failed send, back up as soon as it can be reached
+                match self.send_handshake(sender_name, dst) {
+                    Action::None => (Action::None, now + KEEPALIVE_TIMEOUT),
+                    action => (action, now + REKEY_TIMEOUT),
+                }
+            }
+            HandshakeState::HandshakeSent => {
+                let retry_at = timers.handshake_sent.load() + REKEY_TIMEOUT;
+                if retry_at > now {
+                    return (Action::None, retry_at);
+                }
+
+                tracing::debug!("handshake timed out, retrying");
+                (self.format_handshake(sender_name, dst), now + REKEY_TIMEOUT)
+            }
+            HandshakeState::HandshakeReceived { .. } | HandshakeState::Connected { .. } => {
+                let expire_at = timers.last_received.load() + SESSION_TIMEOUT;
+                if expire_at <= now {
+                    tracing::debug!("session expired");
+                    self.set_state(&mut state, HandshakeState::None);
+                    drop(state);
+
+                    let action = self.send_handshake(sender_name, dst);
+                    return (action, now + REKEY_TIMEOUT);
+                }
+
+                // until the initiator's first data packet arrives there is no session to keep alive
+                if !matches!(*state, HandshakeState::Connected { .. }) {
+                    return (Action::None, expire_at);
+                }
+                let keepalive_at = timers.last_sent.load() + KEEPALIVE_TIMEOUT;
+                if keepalive_at > now {
+                    return (Action::None, expire_at.min(keepalive_at));
+                }
+                drop(state);
+
+                tracing::trace!("sending keepalive");
+                (
+                    self.encapsulate(&[], dst),
+                    expire_at.min(now + KEEPALIVE_TIMEOUT),
+                )
+            }
+        }
+    }
+
+    /// Tells the remote side that the session is over, if there is one
+    pub fn send_disconnect<'a>(&self, dst: &'a mut [u8]) -> Action<'a> {
+        let mut state = self.handshake_state.write();
+        match *state {
+            HandshakeState::HandshakeReceived { remote_idx }
+            | HandshakeState::Connected { remote_idx } => {
+                let packet = Disconnect {
+                    sender_idx: remote_idx,
+                };
+                let n = packet.format(dst);
+
+                self.set_state(&mut state, HandshakeState::None);
+
+                tracing::debug!("sending disconnect");
+                Action::WriteToNetwork(&dst[..n])
+            }
+            _ => Action::None,
+        }
+    }
+
     pub fn encapsulate<'a>(&self, src: &'a [u8], dst: &'a mut [u8]) -> Action<'a> {
-        let state = self.handshake_state.read();
-        if let HandshakeState::Connected { remote_idx } = &*state {
+        if let Some(remote_idx) = self.session() {
             let data = PacketData {
-                sender_idx: *remote_idx,
+                sender_idx: remote_idx,
                 data: src,
             };
             let n = data.format(dst);
+
+            self.timers.last_sent.touch(Instant::now());
+
             Action::WriteToNetwork(&dst[..n])
         } else {
             Action::None
@@ -145,12 +400,24 @@ impl Peer {
     }
 
     pub fn handle_incoming_packet<'a>(&self, packet: Packet<'a>, dst: &'a mut [u8]) -> Action<'a> {
+        self.timers.last_received.touch(Instant::now());
+
         match packet {
             Packet::Empty => Action::None,
             Packet::HandshakeInit(msg) => self.handle_handshake_init(msg, dst),
             Packet::HandshakeResponse(msg) => self.handle_handshake_response(msg, dst),
             Packet::Data(msg) => self.handle_packet_data(msg, dst),
+            Packet::Disconnect(_) => self.handle_disconnect(),
+        }
+    }
+
+    fn handle_disconnect<'a>(&self) -> Action<'a> {
+        let mut state = self.handshake_state.write();
+        if *state != HandshakeState::None {
+            tracing::debug!("received disconnect");
+            self.set_state(&mut state, HandshakeState::None);
         }
+        Action::None
     }
 
     fn handle_handshake_init<'a>(&self, msg: HandshakeInit<'a>, dst: &'a mut [u8]) -> Action<'a> {
@@ -158,9 +425,10 @@ impl Peer {
 
         if let HandshakeState::None | HandshakeState::Connected { .. } = &*state {
             tracing::debug!("received handshake");
-            *state = HandshakeState::HandshakeReceived {
+            let received = HandshakeState::HandshakeReceived {
                 remote_idx: msg.assigned_idx,
             };
+            self.set_state(&mut state, received);
             drop(state);
 
             let local_idx = self.local_idx;
@@ -184,9 +452,10 @@ impl Peer {
         if let HandshakeState::HandshakeSent = &*state {
             tracing::debug!("received handshake response");
 
-            *state = HandshakeState::Connected {
+            let connected = HandshakeState::Connected {
                 remote_idx: msg.assigned_idx,
             };
+            self.set_state(&mut state, connected);
             drop(state);
 
             self.encapsulate(&[], dst)
@@ -196,19 +465,17 @@ impl Peer {
     }
 
     fn handle_packet_data<'a>(&self, msg: PacketData<'a>, _dst: &'a mut [u8]) -> Action<'a> {
-        let state = self.handshake_state.read();
-        match &*state {
-            HandshakeState::Connected { .. } => (),
-            HandshakeState::HandshakeReceived { remote_idx } => {
-                tracing::debug!("received a first data packet, transitioning to Connected");
-                let remote_idx = *remote_idx;
-                drop(state);
-
-                let mut state = self.handshake_state.write();
-                *state = HandshakeState::Connected { remote_idx };
+        if self.session().is_none() {
+            let mut state = self.handshake_state.write();
+            match *state {
+                HandshakeState::Connected { .. } => (),
+                HandshakeState::HandshakeReceived { remote_idx } => {
+                    tracing::debug!("received a first data packet, transitioning to Connected");
+                    self.set_state(&mut state, HandshakeState::Connected { remote_idx });
+                }
+                _ => return Action::None,
             }
-            _ => return Action::None,
-        };
+        }
         match etherparse::Ipv4HeaderSlice::from_slice(msg.data) {
             Ok(iph) => {
                 let src = iph.source_addr();
@@ -219,6 +486,14 @@ impl Peer {
     }
 }
 
+impl<T: AsRef<[u8]>> fmt::Display for PeerName<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0.as_ref();
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        f.write_str(&String::from_utf8_lossy(&bytes[..len]))
+    }
+}
+
 impl std::borrow::Borrow<[u8]> for PeerName<[u8; PEER_NAME_MAX_LEN]> {
     fn borrow(&self) -> &[u8] {
         self.0.as_slice()
@@ -265,3 +540,263 @@ impl PeerName<[u8; PEER_NAME_MAX_LEN]> {
         PeerName(self.0.as_slice())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let peer: Peer = Peer::new();
+        assert!(!peer.is_backlogged());
+
+        assert!(peer.push_backlog(&[0]));
+        for i in 1..BACKLOG_LEN + 10 {
+            assert!(!peer.push_backlog(&[i as u8]));
+        }
+        assert!(peer.is_backlogged());
+        assert_eq!(peer.dropped(), 10);
+
+        // stops at the first packet that would block and retries it next time
+        let mut sent = vec![];
+        let drained = peer.drain_backlog(|data| {
+            if sent.len() == 3 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            sent.push(data[0]);
+            Ok(data.len())
+        });
+        assert!(!drained);
+        assert_eq!(sent, [0, 1, 2]);
+
+        let mut rest = 0;
+        assert!(peer.drain_backlog(|data| {
+            assert_eq!(data[0], (3 + rest) as u8);
+            rest += 1;
+            Ok(data.len())
+        }));
+        assert_eq!(rest, BACKLOG_LEN - 3);
+        assert!(!peer.is_backlogged());
+    }
+
+    #[test]
+    fn test_touch() {
+        let start = Instant::now();
+        let instant = AtomicInstant::new(start);
+
+        instant.touch(start + TICK / 2);
+        assert_eq!(instant.load(), start);
+        instant.touch(start + TICK);
+        assert_eq!(instant.load(), start + TICK);
+    }
+
+    #[test]
+    fn test_session() {
+        let peer: Peer = Peer::new();
+        let name = PeerName::new("a").unwrap();
+        let mut buf = [0u8; 128];
+        peer.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+
+        assert!(matches!(peer.encapsulate(&[], &mut buf), Action::None));
+        peer.send_handshake(name.as_ref(), &mut buf);
+        let response = Packet::HandshakeResponse(HandshakeResponse {
+            assigned_idx: 7,
+            sender_idx: 0,
+        });
+        peer.handle_incoming_packet(response, &mut buf);
+        assert_eq!(peer.session(), Some(7));
+        assert!(matches!(
+            peer.encapsulate(&[], &mut buf),
+            Action::WriteToNetwork(_)
+        ));
+
+        peer.handle_incoming_packet(Packet::Disconnect(Disconnect { sender_idx: 0 }), &mut buf);
+        assert_eq!(peer.session(), None);
+        assert!(matches!(peer.encapsulate(&[], &mut buf), Action::None));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let peer: Peer = Peer::new();
+        let name = PeerName::new("a").unwrap();
+        let mut buf = [0u8; 128];
+        let now = Instant::now();
+
+        // nowhere to send a handshake to yet
+        assert!(matches!(
+            peer.update_timers(name.as_ref(), &mut buf, now).0,
+            Action::None
+        ));
+
+        peer.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+        peer.send_handshake(name.as_ref(), &mut buf);
+        let response = Packet::HandshakeResponse(HandshakeResponse {
+            assigned_idx: 7,
+            sender_idx: 0,
+        });
+        peer.handle_incoming_packet(response, &mut buf);
+        peer.handle_incoming_packet(Packet::Disconnect(Disconnect { sender_idx: 0 }), &mut buf);
+        assert_eq!(*peer.handshake_state.read(), HandshakeState::None);
+
+        let (Action::WriteToNetwork(init), _) = peer.update_timers(name.as_ref(), &mut buf, now)
+        else {
+            panic!("no handshake after the disconnect");
+        };
+        assert!(matches!(
+            Packet::parse_from(init),
+            Ok(Packet::HandshakeInit(_))
+        ));
+        assert_eq!(*peer.handshake_state.read(), HandshakeState::HandshakeSent);
+    }
+}
+
+/// Model checks of concurrent state transitions, see `crate::sync`
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::thread;
+
+    use super::*;
+
+    const BUF_SIZE: usize = 128;
+
+    fn handshake_init(peer: &Peer, assigned_idx: u32) {
+        let name = PeerName::new("remote").unwrap();
+        let init = Packet::HandshakeInit(HandshakeInit {
+            sender_name: name.as_ref(),
+            assigned_idx,
+        });
+        peer.handle_incoming_packet(init, &mut [0u8; BUF_SIZE]);
+    }
+
+    fn data(peer: &Peer) {
+        let data = Packet::Data(PacketData {
+            sender_idx: 0,
+            data: &[],
+        });
+        peer.handle_incoming_packet(data, &mut [0u8; BUF_SIZE]);
+    }
+
+    /// The state, after checking that the lock-free session agrees with it
+    fn state(peer: &Peer) -> HandshakeState {
+        let state = peer.handshake_state.read();
+        let expected = match *state {
+            HandshakeState::Connected { remote_idx } => Some(remote_idx),
+            _ => None,
+        };
+        assert_eq!(peer.session(), expected);
+
+        *state
+    }
+
+    #[test]
+    fn loom_data_races_handshake() {
+        loom::model(|| {
+            let peer: Arc<Peer> = Arc::new(Peer::new());
+            handshake_init(&peer, 1);
+
+            let first_data = {
+                let peer = Arc::clone(&peer);
+                thread::spawn(move || data(&peer))
+            };
+            // the remote restarted and handshakes again
+            handshake_init(&peer, 2);
+            first_data.join().unwrap();
+
+            // a pending handshake ignores new inits: either the data confirmed the old session
+            // and the new one replaced it, or the new init was dropped and the data confirmed
+            // the old one, never the new one
+            match state(&peer) {
+                HandshakeState::HandshakeReceived { remote_idx: 2 }
+                | HandshakeState::Connected { remote_idx: 1 } => (),
+                state => panic!("unexpected state {state:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn loom_disconnect_races_data() {
+        loom::model(|| {
+            let peer: Arc<Peer> = Arc::new(Peer::new());
+            handshake_init(&peer, 1);
+
+            let first_data = {
+                let peer = Arc::clone(&peer);
+                thread::spawn(move || data(&peer))
+            };
+            let disconnect = Packet::Disconnect(Disconnect { sender_idx: 0 });
+            peer.handle_incoming_packet(disconnect, &mut [0u8; BUF_SIZE]);
+            first_data.join().unwrap();
+
+            // a data packet racing the disconnect must not bring the session back
+            assert_eq!(state(&peer), HandshakeState::None);
+            let mut buf = [0u8; BUF_SIZE];
+            assert!(matches!(peer.encapsulate(&[], &mut buf), Action::None));
+        });
+    }
+
+    #[test]
+    fn loom_send_races_response() {
+        loom::model(|| {
+            let peer: Arc<Peer> = Arc::new(Peer::new());
+            peer.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+            let name = PeerName::new("local").unwrap();
+            peer.send_handshake(name.as_ref(), &mut [0u8; BUF_SIZE]);
+
+            let sender = {
+                let peer = Arc::clone(&peer);
+                thread::spawn(move || {
+                    let mut buf = [0u8; BUF_SIZE];
+                    match peer.encapsulate(&[], &mut buf) {
+                        Action::WriteToNetwork(data) => {
+                            let Ok(Packet::Data(msg)) = Packet::parse_from(data) else {
+                                panic!("not a data packet");
+                            };
+                            assert_eq!(msg.sender_idx, 5);
+                        }
+                        Action::None => (),
+                        Action::WriteToTunn(..) => unreachable!(),
+                    }
+                })
+            };
+            let response = Packet::HandshakeResponse(HandshakeResponse {
+                assigned_idx: 5,
+                sender_idx: 0,
+            });
+            peer.handle_incoming_packet(response, &mut [0u8; BUF_SIZE]);
+            sender.join().unwrap();
+
+            assert_eq!(state(&peer), HandshakeState::Connected { remote_idx: 5 });
+        });
+    }
+
+    #[test]
+    fn loom_concurrent_roaming() {
+        loom::model(|| {
+            let peer: Arc<Peer> = Arc::new(Peer::new());
+            let port = |peer: &Peer| peer.endpoint().addr.map_or(0, |addr| addr.port());
+
+            // every update sees the result of the previous one
+            let roam = |peer: Arc<Peer>| {
+                move || {
+                    peer.update_endpoint(|endpoint| {
+                        let port = endpoint.addr.map_or(0, |addr| addr.port());
+                        Some(Endpoint {
+                            addr: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port + 1)),
+                            conn: None,
+                        })
+                    });
+                }
+            };
+            let roamers: Vec<_> = (0..2)
+                .map(|_| thread::spawn(roam(Arc::clone(&peer))))
+                .collect();
+            let seen = port(&peer);
+            assert!(seen <= 2);
+            for roamer in roamers {
+                roamer.join().unwrap();
+            }
+
+            assert_eq!(port(&peer), 2);
+        });
+    }
+}
@@ -1,14 +1,21 @@
 // https://stackoverflow.com/questions/12481245/epoll-wait-on-several-threads-faster/12484467#12484467
 // https://man7.org/linux/man-pages/man7/epoll.7.html
-use std::io;
-use std::os::fd::AsFd;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsFd, BorrowedFd};
 
+use nix::errno::Errno;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::sys::eventfd::{eventfd, EfdFlags};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Token<ID = i32> {
     Tun,
     Sock(ID),
+    Waker,
+    Timer,
+    /// The shared `Poll` nested in a per-thread one
+    Shared,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -22,6 +29,9 @@ where
         match value {
             Token::Tun => 1 << 32,
             Token::Sock(sock_index) => 2 << 32 | (sock_index.into() as u32 as u64),
+            Token::Waker => 3 << 32,
+            Token::Timer => 4 << 32,
+            Token::Shared => 5 << 32,
         }
     }
 }
@@ -37,6 +47,9 @@ where
         let token = match tag {
             1 => Token::Tun,
             2 => Token::Sock((value as i32).into()),
+            3 => Token::Waker,
+            4 => Token::Timer,
+            5 => Token::Shared,
             _ => return Err(UnknownToken),
         };
 
@@ -46,6 +59,29 @@ where
 
 const EPOLL_FLAGS: EpollFlags = EpollFlags::EPOLLIN.union(EpollFlags::EPOLLET);
 
+/// An eventfd to interrupt threads blocked in `Poll::wait`. It is registered level triggered
+/// and never drained, so once woken every waiting thread (now and later) gets a `Token::Waker`.
+pub struct Waker {
+    fd: File,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        Ok(Self { fd: fd.into() })
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        (&self.fd).write_all(&1u64.to_ne_bytes())
+    }
+}
+
+impl AsFd for Waker {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
 pub struct Poll {
     epoll: Epoll,
 }
@@ -68,23 +104,92 @@ impl Poll {
         Ok(())
     }
 
+    /// Adds `EPOLLOUT` to the events of an fd registered with `register_read`, or removes it
+    /// again. Enabling it on a writable fd yields an event right away.
+    pub fn watch_writable<F: AsFd, ID: From<i32> + Into<i32>>(
+        &self,
+        token: Token<ID>,
+        fd: &F,
+        writable: bool,
+    ) -> io::Result<()> {
+        let flags = if writable {
+            EPOLL_FLAGS | EpollFlags::EPOLLOUT
+        } else {
+            EPOLL_FLAGS
+        };
+        let mut event = EpollEvent::new(flags, token.into());
+        self.epoll.modify(fd, &mut event)?;
+
+        Ok(())
+    }
+
+    pub fn register_waker(&self, waker: &Waker) -> io::Result<()> {
+        let event = EpollEvent::new(EpollFlags::EPOLLIN, Token::<i32>::Waker.into());
+        self.epoll.add(waker, event)?;
+
+        Ok(())
+    }
+
+    /// Nests `shared` in this poll, edge triggered: a `Token::Shared` means new events were added
+    /// to `shared`, they have to be taken with `wait` until it returns less than a full `Events`.
+    pub fn register_shared(&self, shared: &Poll) -> io::Result<()> {
+        let event = EpollEvent::new(EPOLL_FLAGS, Token::<i32>::Shared.into());
+        self.epoll.add(shared, event)?;
+
+        Ok(())
+    }
+
     pub fn delete<F: AsFd>(&self, fd: &F) -> io::Result<()> {
         self.epoll.delete(fd)?;
 
         Ok(())
     }
 
-    pub fn wait<ID: From<i32> + Into<i32>>(&self) -> io::Result<Token<ID>> {
-        let mut events = [EpollEvent::empty()];
+    /// Waits up to `timeout` milliseconds (-1 for no limit) for events, returns how many there
+    /// are in `events`. An interrupted wait returns none.
+    pub fn wait(&self, events: &mut Events, timeout: isize) -> io::Result<usize> {
+        events.len = match self.epoll.wait(&mut events.events, timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(events.len)
+    }
+}
 
-        let n = self.epoll.wait(&mut events, -1)?;
-        assert!(n == 1);
+/// Ready fds returned by `Poll::wait`
+pub struct Events {
+    events: Vec<EpollEvent>,
+    len: usize,
+}
 
-        let data = events[0].data();
-        let token = Token::try_from(data)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "unexpected epoll data"))?;
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            events: vec![EpollEvent::empty(); capacity],
+            len: 0,
+        }
+    }
 
-        Ok(token)
+    pub fn capacity(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn iter<ID: From<i32>>(&self) -> impl Iterator<Item = Token<ID>> + '_ {
+        self.events[..self.len].iter().filter_map(|event| {
+            let token = Token::try_from(event.data());
+            if token.is_err() {
+                tracing::warn!("unexpected epoll data {}", event.data());
+            }
+            token.ok()
+        })
+    }
+}
+
+impl AsFd for Poll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.0.as_fd()
     }
 }
 
@@ -101,9 +206,35 @@ mod tests {
             Token::Sock(0),
             Token::Sock(4),
             Token::Sock(i32::MAX),
+            Token::Waker,
+            Token::Timer,
+            Token::Shared,
         ] {
             let num: u64 = token.into();
             assert_eq!(num.try_into(), Ok(token));
         }
     }
+
+    #[test]
+    fn test_wait_multiple() {
+        let poll = Poll::new().unwrap();
+        let socks: Vec<_> = (0..3)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for (i, sock) in socks.iter().enumerate() {
+            poll.register_read(Token::Sock(i as i32), sock).unwrap();
+            sock.send_to(b"x", sock.local_addr().unwrap()).unwrap();
+        }
+
+        let mut events = Events::with_capacity(2);
+        assert_eq!(poll.wait(&mut events, -1).unwrap(), 2);
+        let mut tokens: Vec<Token> = events.iter().collect();
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 1);
+        tokens.extend(events.iter());
+        tokens.sort_by_key(|&token| u64::from(token));
+        assert_eq!(tokens, [Token::Sock(0), Token::Sock(1), Token::Sock(2)]);
+
+        // edge triggered: nothing new arrived
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 0);
+    }
 }
@@ -0,0 +1,75 @@
+//! Locks and atomics of the peer state machine. Built with `--cfg loom` they come from loom,
+//! so that the model checks in `peer.rs` explore every interleaving of the threads using them:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+
+#[cfg(not(loom))]
+pub use arc_swap::{ArcSwap, Guard};
+#[cfg(not(loom))]
+pub use parking_lot::{Mutex, RwLock};
+#[cfg(not(loom))]
+pub use std::sync::atomic::AtomicU64;
+
+#[cfg(loom)]
+pub use loom::sync::atomic::AtomicU64;
+#[cfg(loom)]
+pub use model::{ArcSwap, Guard, Mutex, RwLock};
+
+/// The parking_lot and arc-swap APIs used by `Peer`, over loom's locks
+#[cfg(loom)]
+mod model {
+    use std::sync::Arc;
+
+    pub struct RwLock<T>(loom::sync::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub fn new(value: T) -> Self {
+            Self(loom::sync::RwLock::new(value))
+        }
+
+        /// `Peer` only takes the lock to change the state, the models read it
+        #[cfg(test)]
+        pub fn read(&self) -> loom::sync::RwLockReadGuard<'_, T> {
+            self.0.read().unwrap()
+        }
+
+        pub fn write(&self) -> loom::sync::RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap()
+        }
+    }
+
+    pub struct Mutex<T>(loom::sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Self {
+            Self(loom::sync::Mutex::new(value))
+        }
+
+        pub fn lock(&self) -> loom::sync::MutexGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    /// Loads hand out the current `Arc` itself
+    pub type Guard<T> = T;
+
+    /// A lock rather than lock-free, which makes no difference to what the model can observe:
+    /// every load sees one of the stored values, and stores are atomic
+    pub struct ArcSwap<T>(loom::sync::RwLock<Arc<T>>);
+
+    impl<T> ArcSwap<T> {
+        pub fn from_pointee(value: T) -> Self {
+            Self(loom::sync::RwLock::new(Arc::new(value)))
+        }
+
+        pub fn load(&self) -> Arc<T> {
+            Arc::clone(&self.0.read().unwrap())
+        }
+
+        pub fn store(&self, value: Arc<T>) {
+            *self.0.write().unwrap() = value;
+        }
+    }
+}
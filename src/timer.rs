@@ -0,0 +1,232 @@
+// http://www.cs.columbia.edu/~nahum/w6998/papers/sosp87-timing-wheels.pdf
+// https://man7.org/linux/man-pages/man2/timerfd_create.2.html
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::time::{Duration, Instant};
+
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use parking_lot::Mutex;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+const MAX_TICKS: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+/// Resolution of peer timers, deadlines are rounded up to the next tick
+pub(crate) const TICK: Duration = Duration::from_millis(100);
+
+/// A hierarchical timing wheel: level `l` has `SLOTS` slots of `SLOTS^l` ticks each. Entries
+/// are placed on the lowest level whose span covers their deadline and cascade down as the
+/// wheel turns, so insert is O(1) and advancing is O(1) per tick plus the expired entries.
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick: Duration,
+    now: u64,
+    slots: Vec<Vec<(u64, T)>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(start: Instant, tick: Duration) -> Self {
+        Self {
+            start,
+            tick,
+            now: 0,
+            slots: (0..LEVELS * SLOTS).map(|_| Vec::new()).collect(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, deadline: Instant, value: T) {
+        let at = self.ticks_ceil(deadline);
+        self.len += 1;
+        self.place(at, value);
+    }
+
+    /// Turns the wheel to `now`, returning the values whose deadline has passed
+    pub fn advance(&mut self, now: Instant) -> Vec<T> {
+        let target = self.ticks_floor(now);
+        let mut expired = vec![];
+
+        while self.now < target && self.len > 0 {
+            self.now += 1;
+            // higher levels first, so entries cascading into the current tick fire right away
+            for level in (0..LEVELS).rev() {
+                let shift = level as u32 * SLOT_BITS;
+                if self.now & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = (self.now >> shift) as usize & (SLOTS - 1);
+                let entries = std::mem::take(&mut self.slots[level * SLOTS + slot]);
+                for (at, value) in entries {
+                    if at <= self.now {
+                        self.len -= 1;
+                        expired.push(value);
+                    } else {
+                        self.place(at, value);
+                    }
+                }
+            }
+        }
+        self.now = self.now.max(target);
+
+        expired
+    }
+
+    /// Earliest time `advance` may return something. This can be a cascade point on a higher
+    /// level rather than an actual deadline, which only costs a spurious wakeup.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut next = u64::MAX;
+        for level in 0..LEVELS {
+            let shift = level as u32 * SLOT_BITS;
+            let current = self.now >> shift;
+            for i in 1..=SLOTS as u64 {
+                let slot = (current + i) as usize & (SLOTS - 1);
+                if !self.slots[level * SLOTS + slot].is_empty() {
+                    next = next.min((current + i) << shift);
+                    break;
+                }
+            }
+        }
+
+        let nanos = (self.tick.as_nanos() as u64).saturating_mul(next);
+        Some(self.start + Duration::from_nanos(nanos))
+    }
+
+    fn place(&mut self, at: u64, value: T) {
+        // already due entries go into the next tick
+        let delta = at.max(self.now + 1) - self.now;
+        // deadlines beyond the top level are parked there and re-placed when it cascades
+        let delta = delta.min(MAX_TICKS - 1);
+        let level = ((u64::BITS - 1 - delta.leading_zeros()) / SLOT_BITS) as usize;
+        let slot = ((self.now + delta) >> (level as u32 * SLOT_BITS)) as usize & (SLOTS - 1);
+
+        self.slots[level * SLOTS + slot].push((at, value));
+    }
+
+    fn ticks_ceil(&self, t: Instant) -> u64 {
+        let nanos = t.saturating_duration_since(self.start).as_nanos();
+        nanos.div_ceil(self.tick.as_nanos()) as u64
+    }
+
+    fn ticks_floor(&self, t: Instant) -> u64 {
+        let nanos = t.saturating_duration_since(self.start).as_nanos();
+        (nanos / self.tick.as_nanos()) as u64
+    }
+}
+
+/// Deadlines of peers (by local index) on a timerfd, shared by all event loop threads: whichever
+/// thread gets the `Token::Timer` event collects the expired peers, no thread owns the timers.
+pub struct Timers {
+    fd: TimerFd,
+    inner: Mutex<TimersInner>,
+}
+
+struct TimersInner {
+    wheel: TimerWheel<u32>,
+    armed: Option<Instant>,
+}
+
+impl Timers {
+    pub fn new() -> io::Result<Self> {
+        let fd = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+
+        Ok(Self {
+            fd,
+            inner: Mutex::new(TimersInner {
+                wheel: TimerWheel::new(Instant::now(), TICK),
+                armed: None,
+            }),
+        })
+    }
+
+    pub fn schedule(&self, deadline: Instant, peer_idx: u32) -> io::Result<()> {
+        let mut inner = self.inner.lock();
+        inner.wheel.insert(deadline, peer_idx);
+        self.rearm(&mut inner)
+    }
+
+    /// Peers whose deadline has passed, each has to be scheduled again to keep its timers running
+    pub fn expired(&self) -> io::Result<Vec<u32>> {
+        // clears the readiness of the fd, fails with EAGAIN if another thread got there first
+        let _ = self.fd.wait();
+
+        let mut inner = self.inner.lock();
+        let expired = inner.wheel.advance(Instant::now());
+        self.rearm(&mut inner)?;
+
+        Ok(expired)
+    }
+
+    fn rearm(&self, inner: &mut TimersInner) -> io::Result<()> {
+        let next = inner.wheel.next_deadline();
+        if next == inner.armed {
+            return Ok(());
+        }
+        match next {
+            Some(deadline) => {
+                // a zero timeout would disarm the timer instead
+                let timeout = deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_nanos(1));
+                self.fd.set(
+                    Expiration::OneShot(TimeSpec::from_duration(timeout)),
+                    TimerSetTimeFlags::empty(),
+                )?;
+            }
+            None => self.fd.unset()?,
+        }
+        inner.armed = next;
+
+        Ok(())
+    }
+}
+
+impl AsFd for Timers {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let start = Instant::now();
+        let at = |ticks: u64| start + TICK * ticks as u32;
+        let mut wheel = TimerWheel::new(start, TICK);
+
+        // one per level, plus one past the top level
+        for (i, ticks) in [3, 100, 5000, 300_000, MAX_TICKS + 10]
+            .into_iter()
+            .enumerate()
+        {
+            wheel.insert(at(ticks), i);
+        }
+
+        assert_eq!(wheel.next_deadline(), Some(at(3)));
+        assert_eq!(wheel.advance(at(2)), Vec::<usize>::new());
+        assert_eq!(wheel.advance(at(3)), vec![0]);
+        assert_eq!(wheel.advance(at(99)), Vec::<usize>::new());
+        assert_eq!(wheel.advance(at(100)), vec![1]);
+        assert_eq!(wheel.advance(at(299_999)), vec![2]);
+        assert_eq!(wheel.advance(at(300_000)), vec![3]);
+        assert_eq!(wheel.advance(at(MAX_TICKS + 9)), Vec::<usize>::new());
+        assert_eq!(wheel.advance(at(MAX_TICKS + 10)), vec![4]);
+        assert_eq!(wheel.next_deadline(), None);
+
+        // overdue entries fire on the next tick
+        wheel.insert(start, 5);
+        assert_eq!(wheel.advance(at(MAX_TICKS + 11)), vec![5]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+use std::ops::Range;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::{Arc, Weak};
+
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use parking_lot::Mutex;
+
+use crate::udp::{Dest, RecvBatch, SendBatch};
+
+/// Carries wontun packets between the device and its peers, addressed by `SocketAddrV4`. The
+/// fd is used for readiness: readable when a receive would not block, writable when a send
+/// would not. Sends and receives never block, they fail with `WouldBlock` instead.
+pub trait Transport: AsFd + Send + Sync + Sized + 'static {
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize>;
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)>;
+
+    /// Sends to the address of a transport returned by `connect`
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// A new transport on the same local address that only exchanges packets with `addr`, e.g.
+    /// a connected socket for one peer. `None` where that is not supported.
+    fn connect(&self, _addr: SocketAddrV4) -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Fills `batch`, returning the number of packets received. Fails with `WouldBlock` if
+    /// nothing is queued.
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        batch.recv_each(|buf| self.recv_from(buf))
+    }
+
+    /// Sends `run` of `batch`, all of it through this transport. What is left once a send
+    /// would block is passed to `blocked`, see `SendBatch::flush_with`.
+    fn send_batch(
+        &self,
+        batch: &SendBatch<Self>,
+        run: Range<usize>,
+        blocked: &mut impl FnMut(usize, &[u8]),
+    ) -> io::Result<()> {
+        let mut result = Ok(());
+        for i in run.clone() {
+            let (data, dest) = batch.get(i);
+            let sent = match dest {
+                Dest::Conn(_) => self.send(data),
+                Dest::Addr(addr) => self.send_to(data, *addr),
+            };
+            match sent {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    for i in i..run.end {
+                        blocked(i, batch.get(i).0);
+                    }
+                    return Err(err);
+                }
+                Err(err) => result = Err(err),
+                Ok(_) => (),
+            }
+        }
+
+        result
+    }
+}
+
+/// Datagrams queued per `MemTransport`, further ones are dropped like by a full socket buffer
+const MEM_QUEUE_LEN: usize = 1024;
+
+/// In-memory transports that deliver to each other by address, to run devices without sockets
+#[derive(Clone, Default)]
+pub struct MemNetwork {
+    bound: Arc<Mutex<HashMap<SocketAddrV4, Weak<Datagrams>>>>,
+}
+
+impl MemNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, addr: SocketAddrV4) -> io::Result<MemTransport> {
+        let mut bound = self.bound.lock();
+        if bound
+            .get(&addr)
+            .is_some_and(|inbox| inbox.strong_count() > 0)
+        {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let inbox = Arc::new(Inbox::new(MEM_QUEUE_LEN)?);
+        bound.insert(addr, Arc::downgrade(&inbox));
+
+        Ok(MemTransport {
+            addr,
+            network: self.clone(),
+            inbox,
+        })
+    }
+
+    fn deliver(&self, from: SocketAddrV4, to: SocketAddrV4, buf: &[u8]) -> io::Result<usize> {
+        let inbox = self.bound.lock().get(&to).and_then(Weak::upgrade);
+        // like UDP, datagrams to nowhere are lost silently
+        if let Some(inbox) = inbox {
+            inbox.push((buf.to_vec(), from))?;
+        }
+
+        Ok(buf.len())
+    }
+}
+
+/// A transport bound to an address of a `MemNetwork`
+pub struct MemTransport {
+    addr: SocketAddrV4,
+    network: MemNetwork,
+    inbox: Arc<Datagrams>,
+}
+
+type Datagrams = Inbox<(Vec<u8>, SocketAddrV4)>;
+
+impl MemTransport {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+}
+
+impl Transport for MemTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        self.network.deliver(self.addr, addr, buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        let (data, from) = self.inbox.pop()?;
+        // truncated like a datagram into a short buffer
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+
+        Ok((n, from))
+    }
+
+    fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl AsFd for MemTransport {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inbox.as_fd()
+    }
+}
+
+/// A bounded queue of received messages, with an eventfd that is readable while there are any
+pub(crate) struct Inbox<M> {
+    queue: Mutex<VecDeque<M>>,
+    max_len: usize,
+    ready: File,
+}
+
+impl<M> Inbox<M> {
+    pub fn new(max_len: usize) -> io::Result<Self> {
+        let ready = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        Ok(Self {
+            queue: Mutex::new(VecDeque::new()),
+            max_len,
+            ready: ready.into(),
+        })
+    }
+
+    /// Queues `msg`, or drops it if `max_len` messages are queued already
+    pub fn push(&self, msg: M) -> io::Result<()> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= self.max_len {
+            return Ok(());
+        }
+        queue.push_back(msg);
+        // every write is a new edge for edge triggered polls, even if already readable
+        (&self.ready).write_all(&1u64.to_ne_bytes())
+    }
+
+    /// Fails with `WouldBlock` if nothing is queued
+    pub fn pop(&self) -> io::Result<M> {
+        let mut queue = self.queue.lock();
+        let msg = queue.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+        if queue.is_empty() {
+            (&self.ready).read_exact(&mut [0u8; 8])?;
+        }
+
+        Ok(msg)
+    }
+}
+
+impl<M> AsFd for Inbox<M> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ready.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poll::{Events, Poll, Token};
+
+    #[test]
+    fn test_mem_transport() {
+        let network = MemNetwork::new();
+        let a = network.bind("10.0.0.1:1".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+        assert!(network.bind(a.local_addr()).is_err());
+
+        let poll = Poll::new().unwrap();
+        poll.register_read(Token::Sock(1), &b).unwrap();
+        let mut events = Events::with_capacity(4);
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 0);
+
+        let mut send = SendBatch::default();
+        send.push(b"one", Dest::Addr(b.local_addr()));
+        send.push(b"two", Dest::Addr(b.local_addr()));
+        send.push(b"lost", Dest::Addr("10.0.0.3:1".parse().unwrap()));
+        send.flush(&a).unwrap();
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 1);
+
+        let mut recv = RecvBatch::new(16);
+        assert_eq!(recv.recv(&b).unwrap(), 2);
+        assert_eq!(recv.get(0), (&b"one"[..], Some(a.local_addr())));
+        assert_eq!(recv.get(1), (&b"two"[..], Some(a.local_addr())));
+        let err = recv.recv(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // drained, so only a new datagram makes it readable again
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 0);
+        a.send_to(b"three", b.local_addr()).unwrap();
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+// https://www.kernel.org/doc/html/latest/networking/tuntap.html
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use nix::libc;
+
+use crate::offload::{VirtioNetHdr, VNET_HDR_LEN};
+use crate::tunnel::{TunnelInterface, TunnelQueue};
+
+// the kernel writes the actual interface name back into the ifreq
+nix::ioctl_readwrite_bad!(
+    tunsetiff,
+    nix::request_code_write!(b'T', 202, mem::size_of::<libc::c_int>()),
+    libc::ifreq
+);
+nix::ioctl_write_int_bad!(
+    tunsetpersist,
+    nix::request_code_write!(b'T', 203, mem::size_of::<libc::c_int>())
+);
+nix::ioctl_write_int_bad!(
+    tunsetowner,
+    nix::request_code_write!(b'T', 204, mem::size_of::<libc::c_int>())
+);
+nix::ioctl_write_int_bad!(
+    tunsetoffload,
+    nix::request_code_write!(b'T', 208, mem::size_of::<libc::c_uint>())
+);
+nix::ioctl_write_ptr_bad!(
+    tunsetvnethdrsz,
+    nix::request_code_write!(b'T', 216, mem::size_of::<libc::c_int>()),
+    libc::c_int
+);
+
+/// How to get hold of the tun interface
+#[derive(Debug, Clone)]
+pub struct TunConfig<'a> {
+    /// Interface name, may contain a `%d` (e.g. `wontun%d`) for the kernel to pick a free number
+    pub name: &'a str,
+    /// Only attach to an existing (persistent) interface, never create one. Attaching does not
+    /// require CAP_NET_ADMIN if the interface is owned by the current user.
+    pub attach: bool,
+    /// Expected owner of an attached interface, or owner to assign to a persistent one
+    pub owner: Option<u32>,
+    /// Keep the interface around after wontun exits, so it can be attached to later
+    pub persist: bool,
+    /// Prefix packets with a virtio-net header and enable checksum and TCP segmentation
+    /// offloads, so that reads may return TCP super-packets of up to 64 KiB
+    pub vnet_hdr: bool,
+    /// Number of queues (`IFF_MULTI_QUEUE` if more than one), so that each event loop thread can
+    /// read from its own. An attached interface created without `IFF_MULTI_QUEUE` gets one.
+    pub queues: usize,
+}
+
+impl<'a> TunConfig<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            attach: false,
+            owner: None,
+            persist: false,
+            vnet_hdr: false,
+            queues: 1,
+        }
+    }
+}
+
+/// A tun interface in `IFF_TUN | IFF_NO_PI` mode with one or more queues, each reads and writes
+/// bare ip packets (with a virtio-net header in front if `vnet_hdr` is set)
+pub struct Tun {
+    name: String,
+    queues: Vec<TunQueue>,
+}
+
+/// One file descriptor of a (multi-queue) tun. The kernel spreads received flows across
+/// queues, packets can be written to any of them.
+pub struct TunQueue {
+    file: File,
+    vnet_hdr: bool,
+}
+
+impl Tun {
+    pub fn open(config: &TunConfig) -> io::Result<Self> {
+        if config.name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tun name too long: {}", config.name),
+            ));
+        }
+        // the kernel refuses to attach unless IFF_MULTI_QUEUE is as the interface was created
+        let multi_queue = if config.attach {
+            check_attachable(config.name, config.owner)?
+        } else {
+            config.queues > 1
+        };
+        let num_queues = if multi_queue { config.queues.max(1) } else { 1 };
+        if num_queues < config.queues {
+            tracing::info!("{} has a single queue, attaching to it only", config.name);
+        }
+
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        if config.vnet_hdr {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        if multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+
+        let (first, name) = TunQueue::open(config.name, flags, config.vnet_hdr)?;
+        if config.persist {
+            if let Some(owner) = config.owner {
+                unsafe { tunsetowner(first.as_raw_fd(), owner as libc::c_int) }?;
+            }
+            unsafe { tunsetpersist(first.as_raw_fd(), 1) }?;
+        }
+
+        let mut queues = vec![first];
+        for _ in 1..num_queues {
+            // the actual name, so that a `%d` pattern does not create another interface
+            let (queue, _) = TunQueue::open(&name, flags, config.vnet_hdr)?;
+            queues.push(queue);
+        }
+
+        Ok(Self { name, queues })
+    }
+}
+
+impl TunQueue {
+    fn open(name: &str, flags: libc::c_int, vnet_hdr: bool) -> io::Result<(Self, String)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        ifr.ifr_ifru.ifru_flags = flags as libc::c_short;
+
+        unsafe { tunsetiff(file.as_raw_fd(), &mut ifr) }?;
+
+        if vnet_hdr {
+            unsafe { tunsetvnethdrsz(file.as_raw_fd(), &(VNET_HDR_LEN as libc::c_int)) }?;
+            let offloads = libc::TUN_F_CSUM | libc::TUN_F_TSO4;
+            unsafe { tunsetoffload(file.as_raw_fd(), offloads as libc::c_int) }?;
+        }
+
+        // the kernel writes back the actual name, relevant for `%d` patterns
+        let name: Vec<u8> = ifr
+            .ifr_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        let name = String::from_utf8_lossy(&name).into_owned();
+
+        Ok((Self { file, vnet_hdr }, name))
+    }
+}
+
+impl TunnelInterface for Tun {
+    type Queue = TunQueue;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn queue(&self, i: usize) -> &TunQueue {
+        &self.queues[i % self.queues.len()]
+    }
+
+    fn vnet_hdr(&self) -> bool {
+        self.queues[0].vnet_hdr
+    }
+}
+
+impl TunnelQueue for TunQueue {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.recv_with_hdr(buf)?.0)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_with_hdr(&VirtioNetHdr::default(), buf)
+    }
+
+    /// Reads a packet into `buf`, along with its virtio-net header (all zero without `vnet_hdr`)
+    fn recv_with_hdr(&self, buf: &mut [u8]) -> io::Result<(usize, VirtioNetHdr)> {
+        if !self.vnet_hdr {
+            return Ok(((&self.file).read(buf)?, VirtioNetHdr::default()));
+        }
+
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        let n =
+            (&self.file).read_vectored(&mut [IoSliceMut::new(&mut hdr), IoSliceMut::new(buf)])?;
+        if n < VNET_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "short read of virtio-net header",
+            ));
+        }
+
+        Ok((n - VNET_HDR_LEN, VirtioNetHdr::parse(&hdr)))
+    }
+
+    /// Writes a packet with offload information, e.g. a super-packet for the kernel to segment.
+    /// The header is dropped if the tun was not opened with `vnet_hdr`.
+    fn send_with_hdr(&self, hdr: &VirtioNetHdr, buf: &[u8]) -> io::Result<usize> {
+        if !self.vnet_hdr {
+            return (&self.file).write(buf);
+        }
+
+        let hdr = hdr.format();
+        let n = (&self.file).write_vectored(&[IoSlice::new(&hdr), IoSlice::new(buf)])?;
+
+        Ok(n.saturating_sub(VNET_HDR_LEN))
+    }
+}
+
+impl AsRawFd for TunQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for TunQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// Returns whether the interface was created with `IFF_MULTI_QUEUE`
+fn check_attachable(name: &str, owner: Option<u32>) -> io::Result<bool> {
+    let sys_dir = Path::new("/sys/class/net").join(name);
+    let Ok(tun_flags) = fs::read_to_string(sys_dir.join("tun_flags")) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no existing tun interface named {name}"),
+        ));
+    };
+    let tun_flags = tun_flags.trim();
+    let tun_flags = i32::from_str_radix(tun_flags.trim_start_matches("0x"), 16).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected tun_flags of {name}: {tun_flags}"),
+        )
+    })?;
+
+    if let Some(owner) = owner {
+        // -1 if the interface has no owner
+        let actual = fs::read_to_string(sys_dir.join("owner"))?;
+        if actual.trim().parse::<i64>() != Ok(owner as i64) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "tun interface {name} is owned by {}, not {owner}",
+                    actual.trim()
+                ),
+            ));
+        }
+    }
+
+    Ok(tun_flags & libc::IFF_MULTI_QUEUE != 0)
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::offload::VirtioNetHdr;
+use crate::transport::Inbox;
+
+/// Where the device reads the inner IP packets it sends to peers, and writes the ones it
+/// receives from them. It has one or more queues so that event loop threads can read in
+/// parallel, packets may be written to any queue.
+pub trait TunnelInterface: Send + Sync + 'static {
+    type Queue: TunnelQueue;
+
+    fn name(&self) -> &str;
+
+    fn num_queues(&self) -> usize;
+
+    /// Queue `i`, wrapping around if there are fewer queues
+    fn queue(&self, i: usize) -> &Self::Queue;
+
+    /// Whether packets carry virtio-net headers for offloads, see `TunConfig::vnet_hdr`
+    fn vnet_hdr(&self) -> bool {
+        false
+    }
+}
+
+/// One queue of a `TunnelInterface`. The fd is used for readiness: readable when `recv` would
+/// not block. Reads never block, they fail with `WouldBlock` instead.
+pub trait TunnelQueue: AsFd + Send + Sync {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Like `recv`, along with the virtio-net header of the packet (all zero without offloads)
+    fn recv_with_hdr(&self, buf: &mut [u8]) -> io::Result<(usize, VirtioNetHdr)> {
+        Ok((self.recv(buf)?, VirtioNetHdr::default()))
+    }
+
+    /// Like `send`, with offload information if the interface has `vnet_hdr`
+    fn send_with_hdr(&self, _hdr: &VirtioNetHdr, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf)
+    }
+}
+
+/// Packets injected per `MemTun` queue and not yet read, further ones are dropped
+const MEM_QUEUE_LEN: usize = 1024;
+
+/// An interface that only exists in memory: packets are injected with `inject` as if the host
+/// sent them into the tunnel, and the ones the device writes are taken with `wait_sent`.
+/// Clones share the same interface.
+#[derive(Clone)]
+pub struct MemTun {
+    inner: Arc<MemTunInner>,
+}
+
+struct MemTunInner {
+    name: String,
+    queues: Vec<MemTunQueue>,
+    next_queue: Mutex<usize>,
+    sent: Arc<Sent>,
+}
+
+#[derive(Default)]
+struct Sent {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+    cond: Condvar,
+}
+
+pub struct MemTunQueue {
+    inbox: Inbox<Vec<u8>>,
+    sent: Arc<Sent>,
+}
+
+impl MemTun {
+    pub fn new(name: &str, num_queues: usize) -> io::Result<Self> {
+        let sent = Arc::new(Sent::default());
+        let queues = (0..num_queues.max(1))
+            .map(|_| {
+                Ok(MemTunQueue {
+                    inbox: Inbox::new(MEM_QUEUE_LEN)?,
+                    sent: Arc::clone(&sent),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            inner: Arc::new(MemTunInner {
+                name: name.to_string(),
+                queues,
+                next_queue: Mutex::new(0),
+                sent,
+            }),
+        })
+    }
+
+    /// Hands `packet` to the device, on the queues in turn
+    pub fn inject(&self, packet: &[u8]) -> io::Result<()> {
+        let mut next = self.inner.next_queue.lock();
+        let queue = &self.inner.queues[*next];
+        *next = (*next + 1) % self.inner.queues.len();
+
+        queue.inbox.push(packet.to_vec())
+    }
+
+    /// Next packet written by the device, waiting up to `timeout` for one
+    pub fn wait_sent(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let sent = &self.inner.sent;
+        let mut packets = sent.packets.lock();
+        loop {
+            if let Some(packet) = packets.pop_front() {
+                return Some(packet);
+            }
+            if sent.cond.wait_until(&mut packets, deadline).timed_out() {
+                return packets.pop_front();
+            }
+        }
+    }
+}
+
+impl TunnelInterface for MemTun {
+    type Queue = MemTunQueue;
+
+    fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    fn num_queues(&self) -> usize {
+        self.inner.queues.len()
+    }
+
+    fn queue(&self, i: usize) -> &MemTunQueue {
+        &self.inner.queues[i % self.inner.queues.len()]
+    }
+}
+
+impl TunnelQueue for MemTunQueue {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self.inbox.pop()?;
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+
+        Ok(n)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.packets.lock().push_back(buf.to_vec());
+        self.sent.cond.notify_all();
+
+        Ok(buf.len())
+    }
+}
+
+impl AsFd for MemTunQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inbox.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poll::{Events, Poll, Token};
+
+    #[test]
+    fn test_mem_tun() {
+        let tun = MemTun::new("mem0", 2).unwrap();
+        let handle = tun.clone();
+        assert_eq!(tun.name(), "mem0");
+
+        let poll = Poll::new().unwrap();
+        poll.register_read::<_, i32>(Token::Tun, tun.queue(1))
+            .unwrap();
+        let mut events = Events::with_capacity(4);
+
+        handle.inject(b"first").unwrap();
+        handle.inject(b"second").unwrap();
+        assert_eq!(poll.wait(&mut events, 0).unwrap(), 1);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(tun.queue(0).recv(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(tun.queue(1).recv(&mut buf).unwrap(), 6);
+        let err = tun.queue(1).recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        assert_eq!(handle.wait_sent(Duration::ZERO), None);
+        tun.queue(3).send(b"out").unwrap();
+        let sent = handle.wait_sent(Duration::from_secs(1));
+        assert_eq!(sent.as_deref(), Some(&b"out"[..]));
+    }
+}
@@ -1,22 +1,684 @@
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::ops::Range;
+use std::os::fd::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Arc;
 
-use nix::sys::socket::setsockopt;
+use nix::libc;
 use nix::sys::socket::sockopt;
+use nix::sys::socket::{getsockopt, setsockopt};
 use socket2::{Domain, Protocol, Socket, Type};
 
-pub fn new_socket(port: u16, fwmark: Option<u32>) -> io::Result<UdpSocket> {
-    let socket_addr = SocketAddr::from(([0, 0, 0, 0], port));
+use crate::transport::Transport;
 
+/// With `offload` the socket coalesces received datagrams (UDP_GRO, so receive buffers must be
+/// able to hold `MAX_GRO_SIZE` bytes) and fails early if segmentation (UDP_SEGMENT) is missing.
+pub fn new_socket(port: u16, fwmark: Option<u32>, offload: bool) -> io::Result<UdpSocket> {
+    bind_socket(
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port),
+        fwmark,
+        offload,
+    )
+}
+
+/// A socket on `addr` with `SO_REUSEPORT`. The kernel only groups sockets bound to exactly the
+/// same address, so a socket on a specific local address stays out of the group of those on
+/// the wildcard one, and of its steering program.
+fn bind_socket(addr: SocketAddrV4, fwmark: Option<u32>, offload: bool) -> io::Result<UdpSocket> {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
 
     setsockopt(&socket, sockopt::ReusePort, &true)?;
     if let Some(fwmark) = fwmark {
         setsockopt(&socket, sockopt::Mark, &fwmark)?;
     }
+    if offload {
+        // a size of 0 keeps sends as they are unless they carry a UDP_SEGMENT cmsg
+        setsockopt(&socket, sockopt::UdpGsoSegment, &0)?;
+        setsockopt(&socket, sockopt::UdpGroSegment, &true)?;
+    }
     socket.set_nonblocking(true)?;
 
-    socket.bind(&socket_addr.into())?;
+    socket.bind(&SocketAddr::from(addr).into())?;
 
     Ok(socket.into())
 }
+
+/// `n` sockets bound to the same `port` (the first one picks it if 0) with a steering program
+/// attached, see `steer_by_index`. Socket `i` of the result receives the datagrams steered to
+/// index `i`, as long as no other socket joined the port before them.
+pub fn new_sockets(
+    port: u16,
+    fwmark: Option<u32>,
+    offload: bool,
+    n: usize,
+) -> io::Result<Vec<UdpSocket>> {
+    let first = new_socket(port, fwmark, offload)?;
+    let port = first.local_addr()?.port();
+    let mut sockets = vec![first];
+    for _ in 1..n {
+        sockets.push(new_socket(port, fwmark, offload)?);
+    }
+    if n > 1 {
+        steer_by_index(&sockets[0], n as u32)?;
+    }
+
+    Ok(sockets)
+}
+
+/// Attaches a classic BPF program to the `SO_REUSEPORT` group of `sock`, which picks the
+/// socket for a datagram from the index in bytes 1..5 of its wontun header: the receiver's
+/// index of the peer for data, responses and disconnects. So all packets of a session land on
+/// the same socket, wherever the peer roams. The bytes of the index are xor-folded and taken
+/// modulo `n`. Datagrams too short to have an index go to socket 0.
+fn steer_by_index(sock: &UdpSocket, n: u32) -> io::Result<()> {
+    const LD_W_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const TAX: u16 = (libc::BPF_MISC | libc::BPF_TAX) as u16;
+    const RSH_K: u16 = (libc::BPF_ALU | libc::BPF_RSH | libc::BPF_K) as u16;
+    const XOR_X: u16 = (libc::BPF_ALU | libc::BPF_XOR | libc::BPF_X) as u16;
+    const MOD_K: u16 = (libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K) as u16;
+    const RET_A: u16 = (libc::BPF_RET | libc::BPF_A) as u16;
+
+    let stmt = |code, k| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    // the socket data starts at the UDP payload
+    let mut program = [
+        stmt(LD_W_ABS, 1),
+        stmt(TAX, 0),
+        stmt(RSH_K, 16),
+        stmt(XOR_X, 0),
+        stmt(TAX, 0),
+        stmt(RSH_K, 8),
+        stmt(XOR_X, 0),
+        stmt(MOD_K, n),
+        stmt(RET_A, 0),
+    ];
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            (&fprog as *const libc::sock_fprog).cast(),
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Max number of datagrams moved by one recvmmsg/sendmmsg call
+pub const BATCH_SIZE: usize = 32;
+
+/// Max number of segments the kernel sends for one UDP_SEGMENT datagram
+const UDP_MAX_SEGMENTS: usize = 64;
+
+/// Largest UDP payload over IPv4, which bounds both GSO sends and GRO receives
+pub const MAX_GRO_SIZE: usize = 65507;
+
+/// Room for one cmsg with an int, u64 for alignment
+type CmsgBuf = [u64; 4];
+
+/// Buffers for receiving up to `BATCH_SIZE` datagrams with a single recvmmsg
+pub struct RecvBatch {
+    buf_size: usize,
+    bufs: Vec<u8>,
+    lens: [usize; BATCH_SIZE],
+    segment_sizes: [usize; BATCH_SIZE],
+    addrs: [Option<SocketAddrV4>; BATCH_SIZE],
+    len: usize,
+}
+
+impl RecvBatch {
+    pub fn new(buf_size: usize) -> Self {
+        Self {
+            buf_size,
+            bufs: vec![0; buf_size * BATCH_SIZE],
+            lens: [0; BATCH_SIZE],
+            segment_sizes: [0; BATCH_SIZE],
+            addrs: [None; BATCH_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Fills the batch from `sock`, returning the number of datagrams received. Like `recv_from`
+    /// on a non-blocking socket, fails with `WouldBlock` if nothing is queued.
+    pub fn recv<T: Transport>(&mut self, sock: &T) -> io::Result<usize> {
+        sock.recv_batch(self)
+    }
+
+    /// Fills the batch with datagrams from `recv` until it would block
+    pub fn recv_each(
+        &mut self,
+        mut recv: impl FnMut(&mut [u8]) -> io::Result<(usize, SocketAddrV4)>,
+    ) -> io::Result<usize> {
+        self.len = 0;
+        for (i, buf) in self.bufs.chunks_exact_mut(self.buf_size).enumerate() {
+            match recv(buf) {
+                Ok((n, addr)) => {
+                    self.lens[i] = n;
+                    self.segment_sizes[i] = n;
+                    self.addrs[i] = Some(addr);
+                    self.len += 1;
+                }
+                Err(err) if self.len == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+
+        Ok(self.len)
+    }
+
+    fn recv_mmsg(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        let mut names: [libc::sockaddr_in; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls = [CmsgBuf::default(); BATCH_SIZE];
+
+        for (i, buf) in self.bufs.chunks_exact_mut(self.buf_size).enumerate() {
+            iovecs[i].iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = buf.len();
+            msgs[i].msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+            msgs[i].msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            msgs[i].msg_hdr.msg_controllen = mem::size_of::<CmsgBuf>();
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                BATCH_SIZE as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            self.len = 0;
+            return Err(io::Error::last_os_error());
+        }
+
+        self.len = n as usize;
+        for i in 0..self.len {
+            self.lens[i] = msgs[i].msg_len as usize;
+            self.segment_sizes[i] = gro_segment_size(&msgs[i].msg_hdr).unwrap_or(self.lens[i]);
+            self.addrs[i] =
+                (names[i].sin_family == libc::AF_INET as libc::sa_family_t).then(|| {
+                    SocketAddrV4::new(
+                        Ipv4Addr::from(u32::from_be(names[i].sin_addr.s_addr)),
+                        u16::from_be(names[i].sin_port),
+                    )
+                });
+        }
+
+        Ok(self.len)
+    }
+
+    /// Datagram `i` of the last `recv` and its source address
+    pub fn get(&self, i: usize) -> (&[u8], Option<SocketAddrV4>) {
+        assert!(i < self.len);
+        let start = i * self.buf_size;
+        (&self.bufs[start..start + self.lens[i]], self.addrs[i])
+    }
+
+    /// Size of the datagrams that were coalesced into `i` by GRO, the last one may be shorter.
+    /// Without GRO this is simply the length of `i`.
+    pub fn segment_size(&self, i: usize) -> usize {
+        assert!(i < self.len);
+        self.segment_sizes[i]
+    }
+}
+
+fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+    while !cmsg.is_null() {
+        let c = unsafe { &*cmsg };
+        if c.cmsg_level == libc::SOL_UDP && c.cmsg_type == libc::UDP_GRO {
+            let size = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+            return Some(size as usize);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+    }
+    None
+}
+
+/// Where a queued datagram goes: through a peer's connected socket, or to an address through the
+/// socket given to `SendBatch::flush`
+pub enum Dest<T = UdpSocket> {
+    Conn(Arc<T>),
+    Addr(SocketAddrV4),
+}
+
+impl<T> Clone for Dest<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Dest::Conn(conn) => Dest::Conn(Arc::clone(conn)),
+            Dest::Addr(addr) => Dest::Addr(*addr),
+        }
+    }
+}
+
+impl<T> Dest<T> {
+    fn same_as(&self, other: &Dest<T>) -> bool {
+        match (self, other) {
+            (Dest::Conn(a), Dest::Conn(b)) => Arc::ptr_eq(a, b),
+            (Dest::Addr(a), Dest::Addr(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Outgoing datagrams queued up to be sent with as few sendmmsg calls as possible, consecutive
+/// datagrams for the same socket go out together. With `gso`, consecutive datagrams of the same
+/// size for the same destination are further merged into one UDP_SEGMENT send.
+pub struct SendBatch<T = UdpSocket> {
+    data: Vec<u8>,
+    msgs: Vec<(Range<usize>, Dest<T>)>,
+    gso: bool,
+}
+
+impl<T> Default for SendBatch<T> {
+    fn default() -> Self {
+        Self {
+            data: Vec::new(),
+            msgs: Vec::new(),
+            gso: false,
+        }
+    }
+}
+
+impl<T: Transport> SendBatch<T> {
+    pub fn new(gso: bool) -> Self {
+        Self {
+            gso,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8], dest: Dest<T>) {
+        let start = self.data.len();
+        self.data.extend_from_slice(data);
+        self.msgs.push((start..self.data.len(), dest));
+    }
+
+    pub fn is_full(&self) -> bool {
+        let max = if self.gso {
+            UDP_MAX_SEGMENTS
+        } else {
+            BATCH_SIZE
+        };
+        self.msgs.len() >= max
+    }
+
+    /// Datagram `i` and where it goes
+    pub fn get(&self, i: usize) -> (&[u8], &Dest<T>) {
+        let (ref range, ref dest) = self.msgs[i];
+        (&self.data[range.clone()], dest)
+    }
+
+    /// Sends and clears everything queued, `sock` is used for `Dest::Addr`. Datagrams that can
+    /// not be sent (e.g. the socket buffer is full) are dropped, the last error is returned.
+    pub fn flush(&mut self, sock: &T) -> io::Result<()> {
+        self.flush_with(sock, |_, _| ())
+    }
+
+    /// Like `flush`, but datagrams left over because a socket buffer is full are passed to
+    /// `blocked` along with their position in the batch, instead of being dropped
+    pub fn flush_with(
+        &mut self,
+        sock: &T,
+        mut blocked: impl FnMut(usize, &[u8]),
+    ) -> io::Result<()> {
+        let mut result = Ok(());
+
+        let mut start = 0;
+        while start < self.msgs.len() {
+            let target = self.target(start, sock);
+            let end = (start..self.msgs.len())
+                .find(|&i| !std::ptr::eq(self.target(i, sock), target))
+                .unwrap_or(self.msgs.len());
+            if let Err(err) = target.send_batch(self, start..end, &mut blocked) {
+                result = Err(err);
+            }
+            start = end;
+        }
+
+        self.data.clear();
+        self.msgs.clear();
+
+        result
+    }
+
+    fn target<'a>(&'a self, i: usize, sock: &'a T) -> &'a T {
+        match &self.msgs[i].1 {
+            Dest::Conn(conn) => conn,
+            Dest::Addr(_) => sock,
+        }
+    }
+}
+
+impl SendBatch {
+    fn send_mmsg(
+        &self,
+        fd: RawFd,
+        run: Range<usize>,
+        blocked: &mut impl FnMut(usize, &[u8]),
+    ) -> io::Result<()> {
+        let groups = self.gso_groups(run);
+
+        let mut names: Vec<libc::sockaddr_in> = Vec::with_capacity(groups.len());
+        let mut iovecs: Vec<libc::iovec> = Vec::with_capacity(groups.len());
+        for group in &groups {
+            let (ref first, ref dest) = self.msgs[group.start];
+            let data = &self.data[first.start..self.msgs[group.end - 1].0.end];
+            iovecs.push(libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            });
+            let mut name: libc::sockaddr_in = unsafe { mem::zeroed() };
+            if let Dest::Addr(addr) = dest {
+                name.sin_family = libc::AF_INET as libc::sa_family_t;
+                name.sin_port = addr.port().to_be();
+                name.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            }
+            names.push(name);
+        }
+        let mut controls = vec![CmsgBuf::default(); groups.len()];
+
+        let mut hdrs: Vec<libc::mmsghdr> = Vec::with_capacity(groups.len());
+        for (i, group) in groups.iter().enumerate() {
+            let (ref first, ref dest) = self.msgs[group.start];
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            if let Dest::Addr(_) = dest {
+                hdr.msg_hdr.msg_name = unsafe { names.as_mut_ptr().add(i) } as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            }
+            hdr.msg_hdr.msg_iov = unsafe { iovecs.as_mut_ptr().add(i) };
+            hdr.msg_hdr.msg_iovlen = 1;
+            if group.len() > 1 {
+                hdr.msg_hdr.msg_control =
+                    unsafe { controls.as_mut_ptr().add(i) } as *mut libc::c_void;
+                hdr.msg_hdr.msg_controllen =
+                    unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&hdr.msg_hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, first.len() as u16);
+                }
+            }
+            hdrs.push(hdr);
+        }
+
+        // sendmmsg stops at the first datagram that fails, skip that one and carry on unless
+        // the socket is full
+        let mut result = Ok(());
+        let mut sent = 0;
+        while sent < hdrs.len() {
+            let n = unsafe {
+                libc::sendmmsg(
+                    fd,
+                    hdrs[sent..].as_mut_ptr(),
+                    (hdrs.len() - sent) as libc::c_uint,
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    for i in groups[sent..].iter().flat_map(|group| group.clone()) {
+                        blocked(i, &self.data[self.msgs[i].0.clone()]);
+                    }
+                    return Err(err);
+                }
+                result = Err(err);
+                sent += 1;
+            } else {
+                sent += n as usize;
+            }
+        }
+
+        result
+    }
+
+    /// Splits `run` into datagrams sent as one: a single one without gso, otherwise up to
+    /// `UDP_MAX_SEGMENTS` adjacent ones for the same destination of the same size, except for
+    /// the last which may be shorter
+    fn gso_groups(&self, run: Range<usize>) -> Vec<Range<usize>> {
+        if !self.gso {
+            return run.map(|i| i..i + 1).collect();
+        }
+
+        let mut groups = vec![];
+        let mut start = run.start;
+        while start < run.end {
+            let (ref first, ref dest) = self.msgs[start];
+            let segment_size = first.len();
+            let mut total = segment_size;
+            let mut end = start + 1;
+            while end < run.end && end - start < UDP_MAX_SEGMENTS {
+                let (ref range, ref next_dest) = self.msgs[end];
+                if !next_dest.same_as(dest)
+                    || range.len() > segment_size
+                    || total + range.len() > MAX_GRO_SIZE
+                {
+                    break;
+                }
+                total += range.len();
+                end += 1;
+                if range.len() < segment_size {
+                    break;
+                }
+            }
+            groups.push(start..end);
+            start = end;
+        }
+
+        groups
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        match UdpSocket::recv_from(self, buf)? {
+            (n, SocketAddr::V4(addr)) => Ok((n, addr)),
+            (_, addr) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not an IPv4 address: {addr}"),
+            )),
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    /// A socket on the same port with the same fwmark and offloads, connected to `addr`. It is
+    /// bound to the local address routed to `addr`, so it takes the datagrams of `addr` by its
+    /// better match without joining the reuseport group of the listening sockets.
+    fn connect(&self, addr: SocketAddrV4) -> io::Result<Option<Self>> {
+        let port = self.local_addr()?.port();
+        let fwmark = getsockopt(self, sockopt::Mark)?;
+        let fwmark = (fwmark != 0).then_some(fwmark);
+        let offload = getsockopt(self, sockopt::UdpGroSegment)?;
+
+        // connecting an unbound socket picks the local address the way the routes say
+        let probe = bind_socket(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), fwmark, false)?;
+        UdpSocket::connect(&probe, addr)?;
+        let SocketAddr::V4(local) = probe.local_addr()? else {
+            unreachable!("an IPv4 socket");
+        };
+
+        let conn = bind_socket(SocketAddrV4::new(*local.ip(), port), fwmark, offload)?;
+        UdpSocket::connect(&conn, addr)?;
+
+        Ok(Some(conn))
+    }
+
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        batch.recv_mmsg(self)
+    }
+
+    fn send_batch(
+        &self,
+        batch: &SendBatch,
+        run: Range<usize>,
+        blocked: &mut impl FnMut(usize, &[u8]),
+    ) -> io::Result<()> {
+        batch.send_mmsg(self.as_raw_fd(), run, blocked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let SocketAddr::V4(rx_addr) = rx.local_addr().unwrap() else {
+            unreachable!()
+        };
+        conn.connect(rx_addr).unwrap();
+
+        let mut send = SendBatch::default();
+        send.push(b"one", Dest::Addr(rx_addr));
+        send.push(b"two", Dest::Conn(Arc::clone(&conn)));
+        send.push(b"three", Dest::Addr(rx_addr));
+        send.flush(&tx).unwrap();
+
+        let mut recv = RecvBatch::new(16);
+        let mut received = vec![];
+        while received.len() < 3 {
+            let n = recv.recv(&rx).unwrap_or(0);
+            for i in 0..n {
+                let (data, addr) = recv.get(i);
+                received.push((data.to_vec(), addr.map(SocketAddr::V4)));
+            }
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                (b"one".to_vec(), tx.local_addr().ok()),
+                (b"two".to_vec(), conn.local_addr().ok()),
+                (b"three".to_vec(), tx.local_addr().ok()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gso_gro() {
+        let rx = new_socket(0, None, true).unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rx_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, rx.local_addr().unwrap().port());
+
+        let datagrams: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| vec![i; if i < 4 { 1000 } else { 10 }])
+            .collect();
+        let mut send = SendBatch::new(true);
+        for datagram in &datagrams {
+            send.push(datagram, Dest::Addr(rx_addr));
+        }
+        send.flush(&tx).unwrap();
+
+        // GRO may or may not merge them again, either way the same datagrams come out
+        let mut recv = RecvBatch::new(MAX_GRO_SIZE);
+        let mut received = vec![];
+        while received.len() < datagrams.len() {
+            let n = recv.recv(&rx).unwrap_or(0);
+            for i in 0..n {
+                let data = recv.get(i).0;
+                received.extend(data.chunks(recv.segment_size(i)).map(|d| d.to_vec()));
+            }
+        }
+
+        assert_eq!(received, datagrams);
+    }
+
+    /// Index of the first of `socks` to become readable
+    fn wait_readable(socks: &[&UdpSocket]) -> Option<usize> {
+        let mut fds: Vec<libc::pollfd> = socks
+            .iter()
+            .map(|sock| libc::pollfd {
+                fd: sock.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1000) };
+        assert!(n >= 0, "poll: {}", io::Error::last_os_error());
+        fds.iter().position(|fd| fd.revents & libc::POLLIN != 0)
+    }
+
+    #[test]
+    fn test_steer_by_index() {
+        const N: usize = 4;
+        let socks = new_sockets(0, None, false, N).unwrap();
+        let port = socks[0].local_addr().unwrap().port();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // a connected peer socket on the same port must not disable the steering
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let SocketAddr::V4(peer_addr) = peer.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let conn = Transport::connect(&socks[0], peer_addr).unwrap().unwrap();
+        let mut all: Vec<&UdpSocket> = socks.iter().collect();
+        all.push(&conn);
+
+        let mut hit = [false; N];
+        for idx in 0u32..32 {
+            let mut packet = [3u8; 8];
+            packet[1..5].copy_from_slice(&idx.to_le_bytes());
+            tx.send_to(&packet, ("127.0.0.1", port)).unwrap();
+
+            let w = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+            let w = w ^ (w >> 16);
+            let expected = (w ^ (w >> 8)) as usize % N;
+            hit[expected] = true;
+
+            assert_eq!(wait_readable(&all), Some(expected), "index {idx}");
+            let mut buf = [0u8; 8];
+            let received: Vec<usize> = (0..all.len())
+                .filter(|&i| all[i].recv(&mut buf).is_ok())
+                .collect();
+            assert_eq!(received, [expected], "index {idx}");
+        }
+        assert_eq!(hit, [true; N]);
+
+        peer.send_to(&[3u8; 8], ("127.0.0.1", port)).unwrap();
+        assert_eq!(wait_readable(&all), Some(N));
+    }
+
+    #[test]
+    fn test_port_not_shared() {
+        let sock = new_socket(0, None, false).unwrap();
+        let port = sock.local_addr().unwrap().port();
+
+        // SO_REUSEADDR alone does not get another socket onto the port
+        let other = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        setsockopt(&other, sockopt::ReuseAddr, &true).unwrap();
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        assert!(other.bind(&addr.into()).is_err());
+    }
+}
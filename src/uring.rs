@@ -0,0 +1,354 @@
+// https://man7.org/linux/man-pages/man7/io_uring.7.html
+// https://kernel.dk/io_uring.pdf
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::ops::Range;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::sync::Arc;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use nix::libc;
+
+use crate::udp::Dest;
+
+const RING_ENTRIES: u32 = 256;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Op {
+    Read = 1,
+    Recv,
+    Send,
+    Write,
+    /// Poll in front of a read or receive, which reports the result
+    PollSlot,
+    PollFd,
+    Cancel,
+}
+
+impl Op {
+    fn user_data(self, arg: u32) -> u64 {
+        (self as u64) << 32 | arg as u64
+    }
+
+    fn from_user_data(data: u64) -> Option<(Self, u32)> {
+        let op = match data >> 32 {
+            1 => Op::Read,
+            2 => Op::Recv,
+            3 => Op::Send,
+            4 => Op::Write,
+            5 => Op::PollSlot,
+            6 => Op::PollFd,
+            7 => Op::Cancel,
+            _ => return None,
+        };
+
+        Some((op, data as u32))
+    }
+}
+
+#[derive(Debug)]
+pub enum Completion {
+    /// A read into the start of `slot`
+    Read {
+        slot: usize,
+        res: io::Result<usize>,
+    },
+    /// A datagram received into the start of `slot`
+    Recv {
+        slot: usize,
+        res: io::Result<(usize, SocketAddrV4)>,
+    },
+    /// A send from `slot`
+    Sent {
+        slot: usize,
+        res: io::Result<usize>,
+    },
+    Written(io::Result<usize>),
+    /// The fd passed to `poll` is readable
+    Readable(RawFd),
+}
+
+/// Per-slot message headers, read by the kernel while a send or receive is in flight
+struct Slot<T> {
+    recv_iov: libc::iovec,
+    recv_msg: libc::msghdr,
+    recv_addr: libc::sockaddr_in,
+    send_iov: libc::iovec,
+    send_msg: libc::msghdr,
+    send_addr: libc::sockaddr_in,
+    /// Keeps a connected socket open until the send on it completed
+    conn: Option<Arc<T>>,
+}
+
+/// An io_uring with `num_slots` buffers of `buf_size + out_size` bytes, registered as fixed
+/// buffers. Reads and receives go to the first `buf_size` bytes of a slot, leaving the other
+/// `out_size` for the encapsulated packet or a handshake response. Entries are chained with `push`, so that a slot
+/// is only read into again after what was sent from it is out.
+pub struct Ring<T = UdpSocket> {
+    ring: IoUring,
+    buf_size: usize,
+    slot_size: usize,
+    bufs: Vec<u8>,
+    slots: Box<[Slot<T>]>,
+    inflight: usize,
+}
+
+impl<T: AsFd> Ring<T> {
+    pub fn new(num_slots: usize, buf_size: usize, out_size: usize) -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let slot_size = buf_size + out_size;
+        let mut bufs = vec![0u8; num_slots * slot_size];
+
+        let iovecs: Vec<libc::iovec> = bufs
+            .chunks_mut(slot_size)
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        // the heap allocation of `bufs` never moves and outlives the ring
+        unsafe { ring.submitter().register_buffers(&iovecs) }?;
+
+        let slots = (0..num_slots)
+            .map(|_| unsafe {
+                Slot {
+                    recv_iov: mem::zeroed(),
+                    recv_msg: mem::zeroed(),
+                    recv_addr: mem::zeroed(),
+                    send_iov: mem::zeroed(),
+                    send_msg: mem::zeroed(),
+                    send_addr: mem::zeroed(),
+                    conn: None,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            ring,
+            buf_size,
+            slot_size,
+            bufs,
+            slots,
+            inflight: 0,
+        })
+    }
+
+    /// All of `slot`, what is read into it and the room for the output
+    pub fn buf(&mut self, slot: usize) -> &mut [u8] {
+        let size = self.slot_size;
+        &mut self.bufs[slot * size..(slot + 1) * size]
+    }
+
+    /// Reads from `fd` into the first `buf_size` bytes of `slot`
+    pub fn read(&mut self, fd: RawFd, slot: usize) -> squeue::Entry {
+        let len = self.buf_size as u32;
+        let buf = self.buf(slot).as_mut_ptr();
+
+        opcode::ReadFixed::new(types::Fd(fd), buf, len, slot as u16)
+            .build()
+            .user_data(Op::Read.user_data(slot as u32))
+    }
+
+    /// Writes `range` of `slot` to `fd`
+    pub fn write(&mut self, fd: RawFd, slot: usize, range: Range<usize>) -> squeue::Entry {
+        let len = range.len() as u32;
+        let buf = self.buf(slot)[range].as_ptr();
+
+        opcode::WriteFixed::new(types::Fd(fd), buf, len, slot as u16)
+            .build()
+            .user_data(Op::Write.user_data(slot as u32))
+    }
+
+    /// Receives a datagram from `sock` into the first `buf_size` bytes of `slot`
+    pub fn recv(&mut self, sock: &T, slot: usize) -> squeue::Entry {
+        let len = self.buf_size;
+        let buf = self.buf(slot).as_mut_ptr();
+        let s = &mut self.slots[slot];
+        s.recv_iov = libc::iovec {
+            iov_base: buf.cast(),
+            iov_len: len,
+        };
+        s.recv_msg.msg_iov = &mut s.recv_iov;
+        s.recv_msg.msg_iovlen = 1;
+        s.recv_msg.msg_name = (&mut s.recv_addr as *mut libc::sockaddr_in).cast();
+        s.recv_msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+        opcode::RecvMsg::new(types::Fd(sock.as_fd().as_raw_fd()), &mut s.recv_msg)
+            .build()
+            .user_data(Op::Recv.user_data(slot as u32))
+    }
+
+    /// Sends `range` of `slot` on `sock` to `dest`, a connected socket is used as is
+    pub fn send(
+        &mut self,
+        sock: &T,
+        slot: usize,
+        range: Range<usize>,
+        dest: Dest<T>,
+    ) -> squeue::Entry {
+        let len = range.len();
+        let buf = self.buf(slot)[range].as_ptr();
+        let s = &mut self.slots[slot];
+        s.send_iov = libc::iovec {
+            iov_base: buf as *mut libc::c_void,
+            iov_len: len,
+        };
+        s.send_msg.msg_iov = &mut s.send_iov;
+        s.send_msg.msg_iovlen = 1;
+
+        let fd = match dest {
+            Dest::Conn(conn) => {
+                s.send_msg.msg_name = std::ptr::null_mut();
+                s.send_msg.msg_namelen = 0;
+                let fd = conn.as_fd().as_raw_fd();
+                s.conn = Some(conn);
+                fd
+            }
+            Dest::Addr(addr) => {
+                s.send_addr = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(*addr.ip()).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                s.send_msg.msg_name = (&mut s.send_addr as *mut libc::sockaddr_in).cast();
+                s.send_msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                s.conn = None;
+                sock.as_fd().as_raw_fd()
+            }
+        };
+
+        opcode::SendMsg::new(types::Fd(fd), &s.send_msg)
+            .build()
+            .user_data(Op::Send.user_data(slot as u32))
+    }
+}
+
+impl<T> Ring<T> {
+    /// Waits for `fd` to be readable. Put in front of a read or receive of `slot` that
+    /// completed with `EAGAIN`, as io_uring does not retry on fds opened with `O_NONBLOCK`.
+    pub fn poll_slot(&self, fd: RawFd, slot: usize) -> squeue::Entry {
+        opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as u32)
+            .build()
+            .user_data(Op::PollSlot.user_data(slot as u32))
+    }
+
+    /// Waits for `fd` to be readable, reported as `Completion::Readable`
+    pub fn poll(&self, fd: RawFd) -> squeue::Entry {
+        opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as u32)
+            .build()
+            .user_data(Op::PollFd.user_data(fd as u32))
+    }
+
+    /// Queues `chain`, each entry only starts once the previous one completed (successfully or
+    /// not). Submitted by the next `wait`.
+    pub fn push(&mut self, chain: &[squeue::Entry]) -> io::Result<()> {
+        let mut sq = self.ring.submission();
+        if sq.capacity() - sq.len() < chain.len() {
+            drop(sq);
+            self.ring.submit()?;
+            sq = self.ring.submission();
+        }
+
+        for (i, entry) in chain.iter().enumerate() {
+            let mut entry = entry.clone();
+            if i + 1 < chain.len() {
+                entry = entry.flags(squeue::Flags::IO_HARDLINK);
+            }
+            // the entries point into `self.bufs` and `self.slots`, which outlive the ring
+            unsafe { sq.push(&entry) }.map_err(|_| io::Error::other("submission queue full"))?;
+        }
+        self.inflight += chain.len();
+
+        Ok(())
+    }
+
+    /// Submits the queued entries and waits for at least one completion
+    pub fn wait(&mut self) -> io::Result<()> {
+        self.submit_and_wait(1)
+    }
+
+    /// Submits the queued entries without waiting
+    pub fn submit(&mut self) -> io::Result<()> {
+        self.submit_and_wait(0)
+    }
+
+    fn submit_and_wait(&mut self, want: usize) -> io::Result<()> {
+        match self.ring.submit_and_wait(want) {
+            Ok(_) => Ok(()),
+            Err(err) if err.raw_os_error() == Some(libc::EINTR) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Next completion, `None` once the queue is empty
+    pub fn completion(&mut self) -> Option<Completion> {
+        loop {
+            let cqe = self.ring.completion().next()?;
+            self.inflight -= 1;
+
+            let res = cqe.result();
+            let Some((op, arg)) = Op::from_user_data(cqe.user_data()) else {
+                continue;
+            };
+            let slot = arg as usize;
+            let res = if res < 0 {
+                Err(io::Error::from_raw_os_error(-res))
+            } else {
+                Ok(res as usize)
+            };
+
+            let completion = match op {
+                Op::Read => Completion::Read { slot, res },
+                Op::Recv => {
+                    let addr = &self.slots[slot].recv_addr;
+                    let addr = SocketAddrV4::new(
+                        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                        u16::from_be(addr.sin_port),
+                    );
+                    Completion::Recv {
+                        slot,
+                        res: res.map(|n| (n, addr)),
+                    }
+                }
+                Op::Send => {
+                    self.slots[slot].conn = None;
+                    Completion::Sent { slot, res }
+                }
+                Op::Write => Completion::Written(res),
+                Op::PollFd => Completion::Readable(arg as RawFd),
+                Op::PollSlot | Op::Cancel => continue,
+            };
+
+            return Some(completion);
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        // the kernel may still write into the buffers until all ops are done
+        let cancel = opcode::AsyncCancel2::new(types::CancelBuilder::any())
+            .build()
+            .user_data(Op::Cancel.user_data(0));
+        if self.push(&[cancel]).is_ok() {
+            while self.inflight > 0 && self.wait().is_ok() {
+                while self.completion().is_some() {}
+            }
+        }
+        if self.inflight > 0 {
+            tracing::error!("io_uring ops still in flight, leaking buffers");
+            mem::forget(mem::take(&mut self.bufs));
+            mem::forget(mem::take(&mut self.slots));
+        }
+    }
+}
+
+/// Range of `data` in `buf`, for packets `Peer` returned out of a slot
+pub fn range_in(buf: *const u8, data: &[u8]) -> Range<usize> {
+    let start = data.as_ptr() as usize - buf as usize;
+    start..start + data.len()
+}
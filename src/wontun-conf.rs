@@ -1,29 +1,119 @@
-use clap::Parser;
-use std::path::PathBuf;
-use wontun::Conf;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use wontun::{Conf, Diagnostic, Severity};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(long)]
     conf: PathBuf,
 
+    /// Input format, guessed from the conf file extension if omitted
+    #[arg(long)]
+    from: Option<Format>,
+
+    /// Output format
+    #[arg(long, default_value = "json")]
+    to: Format,
+
     #[arg(long)]
     pretty: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the conf file and print diagnostics, exits non-zero on errors
+    Check,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Ini,
+    Json,
+    Toml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Ini,
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let conf = std::fs::read_to_string(&args.conf)?;
-    let conf = Conf::parse_from(&conf)?;
+    let path = args.conf;
+    let from = args.from.unwrap_or_else(|| Format::from_path(&path));
 
-    let json_str = if args.pretty {
-        serde_json::to_string_pretty(&conf)?
-    } else {
-        serde_json::to_string(&conf)?
+    // only ini input has sources (with line numbers) to point diagnostics at
+    let conf = match from {
+        Format::Ini => Conf::load(&path).map_err(Diagnostic::from),
+        format => load(&path, format).map(|conf| (conf, vec![])),
     };
 
-    println!("{json_str}");
+    if let Some(Command::Check) = args.command {
+        let diagnostics = match conf {
+            Ok((conf, sources)) => conf.validate(&sources),
+            Err(diagnostic) => vec![diagnostic],
+        };
+        for diagnostic in &diagnostics {
+            println!("{diagnostic}");
+        }
+        if diagnostics.iter().any(|d| d.is_error()) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let (conf, _) = conf.map_err(|diagnostic| diagnostic.to_string())?;
+    let output = match (args.to, args.pretty) {
+        (Format::Ini, _) => conf.to_ini(),
+        (Format::Json, false) => serde_json::to_string(&conf)? + "\n",
+        (Format::Json, true) => serde_json::to_string_pretty(&conf)? + "\n",
+        (Format::Toml, false) => toml::to_string(&conf)?,
+        (Format::Toml, true) => toml::to_string_pretty(&conf)?,
+    };
+
+    print!("{output}");
 
     Ok(())
 }
+
+/// Reads a JSON or TOML conf, errors point at the file (and line) like those of ini ones
+fn load(path: &Path, format: Format) -> Result<Conf, Diagnostic> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|err| error(path, None, &format!("cannot read: {err}")))?;
+    if format == Format::Json {
+        serde_json::from_str(&source).map_err(|err| {
+            // the message without the position, which goes into the diagnostic
+            let message = err.to_string();
+            let position = format!(" at line {} column {}", err.line(), err.column());
+            let message = message.strip_suffix(&position).unwrap_or(&message);
+            error(path, Some(err.line()).filter(|&line| line > 0), &message)
+        })
+    } else {
+        toml::from_str(&source).map_err(|err| {
+            let line = err
+                .span()
+                .map(|span| source[..span.start].matches('\n').count() + 1);
+            error(path, line, &err.message())
+        })
+    }
+}
+
+fn error(path: &Path, line: Option<usize>, message: &dyn Display) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        file: Some(path.to_owned()),
+        line,
+        section: None,
+        message: message.to_string(),
+    }
+}
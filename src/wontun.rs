@@ -1,14 +1,26 @@
-use std::{path::PathBuf, sync::Arc};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
 
 use anyhow::{bail, Context};
-use clap::Parser;
+use clap::{ArgGroup, Parser};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{SigSet, Signal};
+use nix::unistd::User;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
-use wontun::{Conf, Device, DeviceConfig, Peer, PeerName};
+use wontun::{
+    Backend, Conf, ConfSource, Device, DeviceConfig, Diagnostic, KillSwitch, Link, Peer, PeerName,
+    TunConfig,
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("tun_mode").args(["tun_attach", "tun_persist"]).multiple(true)))]
 struct Args {
     #[arg(long)]
     conf: PathBuf,
@@ -19,10 +31,61 @@ struct Args {
     #[arg(long)]
     fwmark: Option<u32>,
 
+    /// Number of event loop threads, each with its own tun queue
     #[arg(long)]
     num_threads: Option<usize>,
+
+    /// I/O backend of the event loops: epoll, or uring if built with the io-uring feature
+    #[arg(long, default_value = "epoll")]
+    backend: Backend,
+
+    /// Name of the tun interface (e.g. `wontun%d`), overrides `TunName` of the conf file.
+    /// Defaults to the conf file name without extension.
+    #[arg(long)]
+    tun_name: Option<String>,
+
+    /// Attach to an existing persistent tun interface instead of creating one
+    #[arg(long)]
+    tun_attach: bool,
+
+    /// User (name or uid) owning the tun interface: checked when attaching, assigned with
+    /// --tun-persist
+    #[arg(long, requires = "tun_mode")]
+    tun_owner: Option<String>,
+
+    /// Keep the tun interface after exit, to be attached to later
+    #[arg(long)]
+    tun_persist: bool,
+
+    /// Also reload the conf when it or one of its fragments changes on disk, not only on SIGHUP
+    #[arg(long)]
+    watch: bool,
+
+    /// Enable TSO on the tun and GSO/GRO on the udp sockets, for bulk throughput
+    #[arg(long)]
+    offload: bool,
+
+    /// Leave the address, MTU and routes of the tun interface to be configured by hand
+    #[arg(long)]
+    no_configure: bool,
+
+    /// Drop all outgoing traffic but that through the tunnel and the device's own to its peers,
+    /// until shut down
+    #[arg(long)]
+    kill_switch: bool,
+}
+
+/// What is configured outside the device, the link of the tun, with its routes kept in line with
+/// the conf across reloads, and the kill switch
+#[derive(Default)]
+struct Host {
+    link: Option<Link>,
+    kill_switch: Option<KillSwitch>,
 }
 
+/// Removes the routes and the kill switch when `main` returns, however it does
+struct HostGuard(Arc<Mutex<Host>>);
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
@@ -32,22 +95,59 @@ fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .with_context(|| "setting default tracing subscriber failed")?;
 
-    // tun interface name is derived from the file name of config file
-    let Some(tun_name) = args.conf.file_stem().and_then(|s| s.to_str()) else {
-        bail!("invalid conf file name")
+    let (conf, sources) = Conf::load(&args.conf).with_context(|| "conf file parse error")?;
+
+    if log_diagnostics(&conf.validate(&sources)) {
+        bail!("invalid conf file");
+    }
+
+    // signals are blocked before any thread is spawned, so that all threads inherit the mask and
+    // they are only ever picked up by the sigwait below
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    // tun interface name is derived from the file name of config file, unless given explicitly
+    let tun_name = match args
+        .tun_name
+        .as_deref()
+        .or(conf.interface.tun_name.as_deref())
+    {
+        Some(tun_name) => tun_name,
+        None => match args.conf.file_stem().and_then(|s| s.to_str()) {
+            Some(tun_name) => tun_name,
+            None => bail!("invalid conf file name"),
+        },
     };
-    let conf = std::fs::read_to_string(&args.conf).with_context(|| "failed to read conf file")?;
-    let conf = Conf::parse_from(&conf).with_context(|| "conf file parse error")?;
+    let tun_owner = match args.tun_owner {
+        Some(ref owner) => Some(lookup_uid(owner)?),
+        None => None,
+    };
+
+    let num_threads = args.num_threads.unwrap_or(4).max(1);
 
+    let fwmark = args.fwmark.unwrap_or(19988);
     let mut dev = Device::new(DeviceConfig {
         name: PeerName::new(&conf.interface.name)?,
-        tun_name,
+        tun: TunConfig {
+            name: tun_name,
+            attach: args.tun_attach,
+            owner: tun_owner,
+            persist: args.tun_persist,
+            vnet_hdr: args.offload,
+            queues: num_threads,
+        },
         use_connected_peer: true,
         listen_port: conf.interface.listen_port,
-        fwmark: args.fwmark.or(Some(19988)),
+        fwmark: Some(fwmark),
+        backend: args.backend,
     })
     .with_context(|| "cannot create a Device")?;
+    tracing::info!("using tun interface {}", dev.tun_name());
 
+    let mut peers = Vec::with_capacity(conf.peers.len());
     for peer_conf in &conf.peers {
         let peer_name = PeerName::new(&peer_conf.name)?;
         let mut peer = Peer::default();
@@ -57,19 +157,238 @@ fn main() -> anyhow::Result<()> {
         for (ip, cidr) in &peer_conf.allowed_ips {
             peer.add_allowed_ip(*ip, *cidr);
         }
-        dev.add_peer(peer_name, peer);
+        peers.push((peer_name, peer));
     }
-
+    dev.add_peers(peers);
     let dev = Arc::new(dev);
-    for i in 1..args.num_threads.unwrap_or(4) {
-        let d = Arc::clone(&dev);
+
+    // from here on whatever is set up outside the device is removed on exit, also on errors.
+    // The guard drops before the device, the tun has to be around to remove its routes.
+    let host = Arc::new(Mutex::new(Host::default()));
+    let _guard = HostGuard(Arc::clone(&host));
+    let mut host_config = host.lock().unwrap();
+    if !args.no_configure {
+        let mut link =
+            Link::open(dev.tun_name(), Some(fwmark)).with_context(|| "cannot open the tun link")?;
+        let mtu = conf.interface.mtu.unwrap_or(Conf::DEFAULT_MTU);
+        link.configure(conf.interface.address, mtu)
+            .with_context(|| "cannot configure the tun link")?;
+        host_config.link = Some(link);
+    }
+    if args.kill_switch {
+        let mut kill_switch = KillSwitch::new(dev.tun_name(), fwmark)
+            .with_context(|| "cannot open a netfilter socket")?;
+        kill_switch
+            .install(&dev.endpoints())
+            .with_context(|| "cannot install the kill switch")?;
+        host_config.kill_switch = Some(kill_switch);
+    }
+    host_config
+        .update(&conf)
+        .with_context(|| "cannot set up routes")?;
+    drop(host_config);
+    if args.kill_switch {
+        let host = Arc::clone(&host);
+        dev.on_endpoints_changed(move |endpoints| {
+            let mut host = host.lock().unwrap();
+            if let Some(kill_switch) = host.kill_switch.as_mut() {
+                if let Err(err) = kill_switch.set_endpoints(endpoints) {
+                    tracing::error!("updating the kill switch failed {:?}", err);
+                }
+            }
+        });
+    }
+
+    if args.watch {
+        let dev = Arc::clone(&dev);
+        let host = Arc::clone(&host);
+        let conf_path = args.conf.clone();
         std::thread::spawn(move || {
-            d.event_loop(i);
+            if let Err(err) = watch(&dev, &host, &conf_path, &sources) {
+                tracing::error!("inotify error {:?}", err);
+            }
         });
     }
 
     dev.start()?;
-    dev.event_loop(0);
+    dev.spawn_event_loops(num_threads)?;
+
+    loop {
+        match signals.wait()? {
+            Signal::SIGHUP => {
+                reload(&dev, &host, &args.conf);
+            }
+            signal => {
+                tracing::info!("received {signal}, shutting down");
+                break;
+            }
+        }
+    }
+    dev.shutdown()?;
+    // on an error, the guard retries whatever is left in place
+    host.lock().unwrap().remove()?;
 
     Ok(())
 }
+
+fn lookup_uid(user: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    match User::from_name(user)? {
+        Some(user) => Ok(user.uid.as_raw()),
+        None => bail!("unknown user {user}"),
+    }
+}
+
+/// Logs `diagnostics`, returns true if any of them is an error
+fn log_diagnostics(diagnostics: &[Diagnostic]) -> bool {
+    for diagnostic in diagnostics {
+        if diagnostic.is_error() {
+            tracing::error!("{diagnostic}");
+        } else {
+            tracing::warn!("{diagnostic}");
+        }
+    }
+    diagnostics.iter().any(|d| d.is_error())
+}
+
+impl Host {
+    fn update(&mut self, conf: &Conf) -> io::Result<()> {
+        if let Some(ref mut link) = self.link {
+            // networks routed through the tunnel, the allowed ips of all peers
+            let allowed_ips: Vec<(Ipv4Addr, u8)> = conf
+                .peers
+                .iter()
+                .flat_map(|peer| peer.allowed_ips.iter().copied())
+                .collect();
+            link.set_routes(&allowed_ips)?;
+        }
+        Ok(())
+    }
+
+    /// Undoes `update`, every part of it even if removing another fails
+    fn remove(&mut self) -> io::Result<()> {
+        let routes = self.link.as_mut().map_or(Ok(()), Link::remove_routes);
+        let kill_switch = self.kill_switch.as_mut().map_or(Ok(()), KillSwitch::remove);
+        routes.and(kill_switch)
+    }
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        let mut host = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = host.remove() {
+            tracing::error!("removing routes or the kill switch failed {:?}", err);
+        }
+    }
+}
+
+/// Returns the files read, if the conf could be loaded
+fn reload(dev: &Device, host: &Mutex<Host>, conf_path: &Path) -> Option<Vec<ConfSource>> {
+    tracing::info!("reloading {}", conf_path.display());
+
+    let (conf, sources) = match Conf::load(conf_path) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            log_diagnostics(&[err.into()]);
+            return None;
+        }
+    };
+    if log_diagnostics(&conf.validate(&sources)) {
+        tracing::error!("invalid conf file, keeping the running configuration");
+        return Some(sources);
+    }
+    if let Err(err) = dev.reload(&conf) {
+        tracing::error!("reload failed {:?}", err);
+    }
+    if let Err(err) = host.lock().unwrap().update(&conf) {
+        tracing::error!("updating routes failed {:?}", err);
+    }
+
+    Some(sources)
+}
+
+fn watch(
+    dev: &Device,
+    host: &Mutex<Host>,
+    conf_path: &Path,
+    sources: &[ConfSource],
+) -> nix::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_CREATE;
+
+    let mut watched = HashMap::new();
+    let mut dirs = watch_list(conf_path, sources);
+    loop {
+        watched.retain(|wd, dir| {
+            let keep = dirs.contains_key(dir);
+            if !keep {
+                // fails if the directory is gone, its watch went with it
+                let _ = inotify.rm_watch(*wd);
+            }
+            keep
+        });
+        for dir in dirs.keys() {
+            if watched.values().any(|watched| watched == dir) {
+                continue;
+            }
+            match inotify.add_watch(dir, flags) {
+                Ok(wd) => {
+                    watched.insert(wd, dir.clone());
+                }
+                Err(err) => tracing::warn!("cannot watch {}: {err}", dir.display()),
+            }
+        }
+
+        let events = inotify.read_events()?;
+        let conf_changed = events.iter().any(|event| {
+            let (Some(dir), Some(name)) = (watched.get(&event.wd), event.name.as_deref()) else {
+                return false;
+            };
+            // files are picked up once written, directories once they appear
+            if event.mask.contains(AddWatchFlags::IN_CREATE)
+                && !event.mask.contains(AddWatchFlags::IN_ISDIR)
+            {
+                return false;
+            }
+            dirs[dir].contains(name) || Path::new(name).extension() == Some(OsStr::new("conf"))
+        });
+        if conf_changed {
+            if let Some(sources) = reload(dev, host, conf_path) {
+                dirs = watch_list(conf_path, &sources);
+            }
+        }
+    }
+}
+
+/// Directories to watch for changes to the conf at `conf_path`, with the names of the files
+/// in each that `sources` were read from. Directories rather than files are watched, editors
+/// tend to replace files on save, and a drop-in directory may appear later.
+fn watch_list(conf_path: &Path, sources: &[ConfSource]) -> HashMap<PathBuf, HashSet<OsString>> {
+    let mut dirs: HashMap<PathBuf, HashSet<OsString>> = HashMap::new();
+    let mut add = |path: &Path| {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let names = dirs.entry(dir.to_owned()).or_default();
+        if let Some(name) = path.file_name() {
+            names.insert(name.to_owned());
+        }
+    };
+    add(conf_path);
+    for path in sources.iter().filter_map(|source| source.path.as_deref()) {
+        add(path);
+    }
+    let drop_in = Conf::drop_in_dir(conf_path);
+    add(&drop_in);
+    if drop_in.is_dir() {
+        dirs.entry(drop_in).or_default();
+    }
+
+    dirs
+}
@@ -0,0 +1,146 @@
+mod sim;
+
+use std::time::Duration;
+
+use sim::{ipv4_packet, payload_of, source_of, Sim};
+use wontun::Impairment;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The topology of the sample confs: clients A and B only know the server, which forwards
+/// between them
+fn hub() -> Sim {
+    impaired_hub(Impairment::default())
+}
+
+fn impaired_hub(impairment: Impairment) -> Sim {
+    let mut sim = Sim::new();
+    sim.node("server", [10, 10, 0, 1], "172.18.0.2:19988")
+        .impair(impairment.clone(), 1)
+        .peer("client-B", None, &[([10, 10, 0, 2], 32)])
+        .peer("client-A", None, &[([10, 10, 0, 3], 32)])
+        .forward()
+        .start();
+    for (name, ip, addr) in [
+        ("client-B", [10, 10, 0, 2], "172.18.0.3:19988"),
+        ("client-A", [10, 10, 0, 3], "172.18.0.4:19988"),
+    ] {
+        sim.node(name, ip, addr)
+            .impair(impairment.clone(), ip[3].into())
+            .peer("server", Some("172.18.0.2:19988"), &[([10, 10, 0, 1], 24)])
+            .start();
+    }
+    sim
+}
+
+#[test]
+fn test_handshake() {
+    let sim = hub();
+
+    assert!(sim.ping("client-A", "server", TIMEOUT));
+    // the server had no endpoint for A, it was learned from the handshake
+    assert!(sim.ping("server", "client-A", TIMEOUT));
+    assert!(sim.ping("client-B", "server", TIMEOUT));
+}
+
+#[test]
+fn test_hub_forwarding() {
+    let sim = hub();
+
+    assert!(sim.ping("client-B", "server", TIMEOUT));
+    assert!(sim.ping("client-A", "client-B", TIMEOUT));
+    assert!(sim.ping("client-B", "client-A", TIMEOUT));
+
+    let (a, b) = (&sim["client-A"], &sim["client-B"]);
+    a.send(b.ip(), b"via server");
+    let packet = b.recv(TIMEOUT).unwrap();
+    assert_eq!(source_of(&packet), Some(a.ip()));
+    assert_eq!(payload_of(&packet), b"via server");
+}
+
+#[test]
+fn test_allowed_ips() {
+    let sim = hub();
+    assert!(sim.ping("client-A", "server", TIMEOUT));
+
+    // A may only send from its own address
+    let (a, server) = (&sim["client-A"], &sim["server"]);
+    a.inject(&ipv4_packet([10, 10, 0, 2].into(), server.ip(), b"spoofed"));
+    a.send(server.ip(), b"genuine");
+    let packet = server.recv(TIMEOUT).unwrap();
+    assert_eq!(payload_of(&packet), b"genuine");
+
+    // and nothing is routed to addresses no peer is allowed
+    server.send([10, 10, 0, 9].into(), b"nowhere");
+    assert!(a.recv(Duration::from_millis(200)).is_none());
+}
+
+#[test]
+fn test_roaming() {
+    let mut sim = hub();
+    assert!(sim.ping("client-A", "client-B", TIMEOUT));
+
+    sim.restart("client-A", "172.18.0.5:40000");
+    assert_eq!(sim["client-A"].addr().port(), 40000);
+    assert!(sim.ping("client-A", "server", TIMEOUT));
+    assert!(sim.ping("client-B", "client-A", TIMEOUT));
+}
+
+#[test]
+fn test_impaired_network() {
+    let sim = impaired_hub(Impairment {
+        loss: 0.2,
+        duplicate: 0.2,
+        latency: Duration::from_millis(10),
+        jitter: Duration::from_millis(20),
+        reorder: 0.1,
+        rebind: 0.0,
+    });
+
+    // lost handshakes are only retried after `REKEY_TIMEOUT`
+    let timeout = Duration::from_secs(20);
+    assert!(sim.ping("client-A", "client-B", timeout));
+    assert!(sim.ping("client-B", "client-A", timeout));
+}
+
+#[test]
+fn test_nat_rebinding() {
+    let sim = hub();
+    assert!(sim.ping("client-A", "client-B", TIMEOUT));
+
+    // the server keeps sending to the old ports until the clients are heard from again
+    sim["server"].transport().rebind();
+    assert!(!sim.ping("server", "client-A", Duration::from_millis(300)));
+    assert!(sim.ping("client-A", "server", TIMEOUT));
+    assert!(sim.ping("server", "client-A", TIMEOUT));
+    assert!(sim.ping("client-B", "client-A", TIMEOUT));
+}
+
+#[test]
+fn test_reload() {
+    let mut sim = hub();
+    // C waits to be reached, so that its handshakes do not cross those of the server
+    sim.node("client-C", [10, 10, 0, 4], "172.18.0.5:19988")
+        .peer("server", None, &[([10, 10, 0, 1], 24)])
+        .start();
+    assert!(sim.ping("client-A", "server", TIMEOUT));
+    assert!(sim.ping("client-B", "server", TIMEOUT));
+    assert!(!sim.ping("server", "client-C", Duration::from_millis(300)));
+
+    // B removed, A moved to another address, C added with an endpoint to reach it at
+    sim.reload(
+        "server",
+        "[Peer]\nName=client-A\nAllowedIPs=10.10.0.7/32\n\n\
+         [Peer]\nName=client-C\nEndpoint=172.18.0.5:19988\nAllowedIPs=10.10.0.4/32\n",
+    );
+    assert!(sim.ping("server", "client-C", TIMEOUT));
+    assert!(!sim.ping("client-B", "server", Duration::from_millis(300)));
+    assert!(!sim.ping("client-A", "server", Duration::from_millis(300)));
+
+    // without its endpoint, C is only reached again once it is heard from, at the address it
+    // learned from the server's handshake
+    sim.reload("server", "[Peer]\nName=client-C\nAllowedIPs=10.10.0.4/32\n");
+    assert!(!sim.ping("server", "client-C", Duration::from_millis(300)));
+    assert!(sim.ping("client-C", "server", TIMEOUT));
+    assert!(sim.ping("server", "client-C", TIMEOUT));
+}
@@ -0,0 +1,550 @@
+//! Runs the `wontun` binary in network namespaces, in the topology of the sample confs: a
+//! server that forwards between clients A and B, each client on its own veth link to it.
+//! Needs root and `ip`, the tests are skipped otherwise.
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nix::libc;
+use nix::sys::signal::{self, Signal};
+use nix::sys::socket::{setsockopt, sockopt};
+use nix::unistd::{Pid, Uid};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+const TUN_NAME: &str = "wt0";
+const LISTEN_PORT: u16 = 19988;
+/// Default of `wontun --fwmark`
+const FWMARK: u32 = 19988;
+/// Lost handshakes are retried after `REKEY_TIMEOUT`, so allow for one retry
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(12);
+/// A TCP transfer that makes no progress for this long failed, e.g. as a node went down
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Node {
+    name: &'static str,
+    tunnel_ip: Ipv4Addr,
+    conf: String,
+    /// Extra arguments to `wontun`
+    args: Vec<&'static str>,
+}
+
+/// Variations of `Topology::hub`
+#[derive(Clone, Copy)]
+struct Hub {
+    /// Routed by the clients through the server
+    allowed_ips: &'static str,
+    /// Extra arguments to every `wontun`
+    args: &'static [&'static str],
+    /// Extra arguments to the `wontun` of the clients only
+    client_args: &'static [&'static str],
+    /// `Mtu` of the tun interfaces, the veth links get room for the tunnel overhead on top
+    mtu: Option<u16>,
+    /// The clients attach to a persistent tun made by `ip tuntap`, which has a single queue
+    attach: bool,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            allowed_ips: "10.10.0.1/24",
+            args: &[],
+            client_args: &[],
+            mtu: None,
+            attach: false,
+        }
+    }
+}
+
+/// Namespaces `<prefix>-<node>` with a running `wontun` each, torn down on drop
+struct Topology {
+    prefix: String,
+    dir: PathBuf,
+    namespaces: Vec<String>,
+    children: Vec<Wontun>,
+}
+
+struct Wontun {
+    ns: String,
+    process: Child,
+}
+
+fn can_run() -> bool {
+    let ok = Uid::effective().is_root()
+        && PathBuf::from("/dev/net/tun").exists()
+        && Command::new("ip").arg("-V").output().is_ok();
+    if !ok {
+        eprintln!("skipped: needs root, /dev/net/tun and ip");
+    }
+    ok
+}
+
+/// Runs `ip` with the whitespace separated `args`
+fn ip(args: &str) {
+    let status = Command::new("ip")
+        .args(args.split_whitespace())
+        .status()
+        .expect("running ip");
+    assert!(status.success(), "ip {args}");
+}
+
+/// Output of `ip` with the whitespace separated `args`, empty if it fails
+fn output(args: &str) -> String {
+    let output = Command::new("ip")
+        .args(args.split_whitespace())
+        .stderr(Stdio::null())
+        .output()
+        .expect("running ip");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+impl Topology {
+    /// The server at 10.10.0.1 with a link to each client, A at 10.10.0.3 and B at 10.10.0.2
+    fn hub(tag: &str) -> Self {
+        Self::hub_with(tag, Hub::default())
+    }
+
+    fn hub_with(tag: &str, hub: Hub) -> Self {
+        let prefix = format!("wt{}-{tag}", std::process::id());
+        let dir = std::env::temp_dir().join(&prefix);
+        fs::create_dir_all(&dir).unwrap();
+        let mut topology = Self {
+            prefix,
+            dir,
+            namespaces: Vec::new(),
+            children: Vec::new(),
+        };
+
+        let server = topology.netns("server");
+        ip(&format!(
+            "netns exec {server} sysctl -qw net.ipv4.ip_forward=1"
+        ));
+        let clients = [
+            ("client-B", Ipv4Addr::new(10, 10, 0, 2), 2),
+            ("client-A", Ipv4Addr::new(10, 10, 0, 3), 3),
+        ];
+        let mtu = hub
+            .mtu
+            .map(|mtu| format!("Mtu={mtu}\n"))
+            .unwrap_or_default();
+        let mut server_conf = "[Interface]\nName=server\nAddress=10.10.0.1/24\n".to_string();
+        server_conf += &format!("ListenPort={LISTEN_PORT}\n{mtu}");
+        for (name, tunnel_ip, _) in clients {
+            server_conf += &format!("\n[Peer]\nName={name}\nAllowedIPs={tunnel_ip}/32\n");
+        }
+        topology.start(&Node {
+            name: "server",
+            tunnel_ip: Ipv4Addr::new(10, 10, 0, 1),
+            conf: server_conf,
+            args: hub.args.to_vec(),
+        });
+
+        for (name, tunnel_ip, link) in clients {
+            let ns = topology.netns(name);
+            let (server_addr, client_addr) =
+                (format!("172.18.{link}.1"), format!("172.18.{link}.2"));
+            let veth = format!("veth-{link}");
+            ip(&format!(
+                "link add {veth} netns {server} type veth peer name veth-s netns {ns}"
+            ));
+            ip(&format!("-n {server} addr add {server_addr}/24 dev {veth}"));
+            ip(&format!("-n {server} link set {veth} up"));
+            ip(&format!("-n {ns} addr add {client_addr}/24 dev veth-s"));
+            ip(&format!("-n {ns} link set veth-s up"));
+            if hub.mtu.is_some() {
+                ip(&format!("-n {server} link set {veth} mtu 9000"));
+                ip(&format!("-n {ns} link set veth-s mtu 9000"));
+            }
+
+            let mut args = [hub.args, hub.client_args].concat();
+            if hub.attach {
+                ip(&format!("-n {ns} tuntap add dev {TUN_NAME} mode tun"));
+                args.push("--tun-attach");
+            }
+
+            let conf = format!(
+                "[Interface]\nName={name}\nAddress={tunnel_ip}/24\n{mtu}\n\
+                 [Peer]\nName=server\nEndpoint={server_addr}:{LISTEN_PORT}\nAllowedIPs={}\n",
+                hub.allowed_ips
+            );
+            topology.start(&Node {
+                name,
+                tunnel_ip,
+                conf,
+                args,
+            });
+        }
+
+        topology
+    }
+
+    fn netns(&mut self, node: &str) -> String {
+        let ns = format!("{}-{node}", self.prefix);
+        ip(&format!("netns add {ns}"));
+        ip(&format!("-n {ns} link set lo up"));
+        self.namespaces.push(ns.clone());
+        ns
+    }
+
+    fn ns(&self, node: &str) -> String {
+        format!("{}-{node}", self.prefix)
+    }
+
+    /// Starts `wontun` in the namespace of `node` and waits until it has configured its tun
+    fn start(&mut self, node: &Node) {
+        let ns = self.ns(node.name);
+        let conf = self.dir.join(format!("{}.conf", node.name));
+        fs::write(&conf, &node.conf).unwrap();
+        let log = File::create(self.dir.join(format!("{}.log", node.name))).unwrap();
+
+        let child = Command::new("ip")
+            .args(["netns", "exec", &ns, env!("CARGO_BIN_EXE_wontun")])
+            .arg("--conf")
+            .arg(&conf)
+            .args([
+                "--tun-name",
+                TUN_NAME,
+                "--num-threads",
+                "2",
+                "--log-level",
+                "debug",
+            ])
+            .args(&node.args)
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .expect("starting wontun");
+        self.children.push(Wontun {
+            ns: ns.clone(),
+            process: child,
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output(&format!("-n {ns} addr show dev {TUN_NAME} up"))
+            .contains(&format!("inet {}/24", node.tunnel_ip))
+        {
+            assert!(
+                Instant::now() < deadline,
+                "{TUN_NAME} of {} was not configured",
+                node.name
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Stops the `wontun` of `node`, the namespace stays
+    fn stop(&mut self, node: &str) {
+        let ns = self.ns(node);
+        let i = self
+            .children
+            .iter()
+            .position(|child| child.ns == ns)
+            .unwrap();
+        let mut child = self.children.remove(i);
+        signal::kill(Pid::from_raw(child.process.id() as i32), Signal::SIGTERM).unwrap();
+        assert!(child.process.wait().unwrap().success());
+    }
+}
+
+impl Drop for Topology {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = signal::kill(Pid::from_raw(child.process.id() as i32), Signal::SIGTERM);
+            let _ = child.process.wait();
+        }
+        for ns in &self.namespaces {
+            let _ = Command::new("ip").args(["netns", "del", ns]).status();
+        }
+        if !thread::panicking() {
+            let _ = fs::remove_dir_all(&self.dir);
+        } else {
+            eprintln!("logs left in {}", self.dir.display());
+        }
+    }
+}
+
+/// Runs `f` on a thread in namespace `ns`
+fn in_netns<R: Send + 'static>(ns: &str, f: impl FnOnce() -> R + Send + 'static) -> JoinHandle<R> {
+    let netns = File::open(format!("/var/run/netns/{ns}")).unwrap();
+    thread::spawn(move || {
+        // only the calling thread moves
+        let res = unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) };
+        assert_eq!(res, 0, "setns: {}", std::io::Error::last_os_error());
+        f()
+    })
+}
+
+/// Sends a UDP datagram from `ns` to `dst` with the fwmark of the device, returns whether the
+/// kernel let it out
+fn send_marked(ns: &str, dst: SocketAddrV4) -> bool {
+    in_netns(ns, move || {
+        let sock = UdpSocket::bind("0.0.0.0:0").unwrap();
+        setsockopt(&sock, sockopt::Mark, &FWMARK).unwrap();
+        sock.send_to(b"probe", dst).is_ok()
+    })
+    .join()
+    .unwrap()
+}
+
+/// Sends ICMP echo requests from `ns` to `dst` until one is answered
+fn ping(ns: &str, dst: Ipv4Addr, timeout: Duration) -> bool {
+    in_netns(ns, move || {
+        let sock = Socket::new(
+            Domain::IPV4,
+            Type::from(libc::SOCK_RAW),
+            Some(Protocol::ICMPV4),
+        )
+        .unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let id = std::process::id() as u16;
+        let deadline = Instant::now() + timeout;
+        let mut seq = 0u16;
+        while Instant::now() < deadline {
+            seq = seq.wrapping_add(1);
+            let request = echo_request(id, seq);
+            let dst = SockAddr::from(SocketAddrV4::new(dst, 0));
+            if sock.send_to(&request, &dst).is_err() {
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            }
+            let mut buf = [0u8; 1500];
+            while let Ok(n) = (&sock).read(&mut buf) {
+                // raw sockets receive the ip header too
+                let ihl = ((buf[0] & 0xf) as usize) * 4;
+                let reply = &buf[ihl..n];
+                if reply.len() >= 8 && reply[0] == 0 && reply[4..8] == request[4..8] {
+                    return true;
+                }
+            }
+        }
+        false
+    })
+    .join()
+    .unwrap()
+}
+
+fn echo_request(id: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![8, 0, 0, 0];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(b"wontun netns ping");
+
+    let mut sum = packet
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[2..4].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    packet
+}
+
+#[test]
+fn test_ping() {
+    if !can_run() {
+        return;
+    }
+    let topology = Topology::hub("ping");
+
+    let server = Ipv4Addr::new(10, 10, 0, 1);
+    assert!(ping(&topology.ns("client-A"), server, CONNECT_TIMEOUT));
+    assert!(ping(&topology.ns("client-B"), server, CONNECT_TIMEOUT));
+    assert!(ping(
+        &topology.ns("server"),
+        Ipv4Addr::new(10, 10, 0, 3),
+        CONNECT_TIMEOUT
+    ));
+}
+
+#[test]
+fn test_hub_forwarding() {
+    if !can_run() {
+        return;
+    }
+    let topology = Topology::hub("hub");
+    let (a, b) = (topology.ns("client-A"), topology.ns("client-B"));
+
+    // both clients have to be known to the server before it can forward between them
+    assert!(ping(&b, Ipv4Addr::new(10, 10, 0, 1), CONNECT_TIMEOUT));
+    assert!(ping(&a, Ipv4Addr::new(10, 10, 0, 2), CONNECT_TIMEOUT));
+    assert!(ping(&b, Ipv4Addr::new(10, 10, 0, 3), CONNECT_TIMEOUT));
+}
+
+#[test]
+fn test_tcp() {
+    if !can_run() {
+        return;
+    }
+    transfer(&Topology::hub("tcp"));
+}
+
+#[test]
+fn test_offload_full_mtu() {
+    if !can_run() {
+        return;
+    }
+    // segments of super-packets from the tun fill the MTU, and must still fit encapsulated
+    transfer(&Topology::hub_with(
+        "offload",
+        Hub {
+            args: &["--offload"],
+            mtu: Some(1500),
+            ..Hub::default()
+        },
+    ));
+}
+
+/// Sends 4 MiB over TCP from client A to client B, through the server
+fn transfer(topology: &Topology) {
+    let (a, b) = (topology.ns("client-A"), topology.ns("client-B"));
+    let b_addr = SocketAddrV4::new(Ipv4Addr::new(10, 10, 0, 2), 5001);
+    assert!(ping(&b, Ipv4Addr::new(10, 10, 0, 1), CONNECT_TIMEOUT));
+    assert!(ping(&a, *b_addr.ip(), CONNECT_TIMEOUT));
+
+    let listener = in_netns(&b, move || TcpListener::bind(b_addr).unwrap())
+        .join()
+        .unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(STALL_TIMEOUT)).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        stream
+            .write_all(&(received.len() as u64).to_be_bytes())
+            .unwrap();
+        received
+    });
+
+    let sent: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+    let expected = sent.clone();
+    let acked = in_netns(&a, move || {
+        let mut stream =
+            TcpStream::connect_timeout(&b_addr.into(), Duration::from_secs(5)).unwrap();
+        stream.set_write_timeout(Some(STALL_TIMEOUT)).unwrap();
+        stream.set_read_timeout(Some(STALL_TIMEOUT)).unwrap();
+        stream.write_all(&sent).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut len = [0u8; 8];
+        stream.read_exact(&mut len).unwrap();
+        u64::from_be_bytes(len)
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(acked, expected.len() as u64);
+    assert!(server.join().unwrap() == expected);
+}
+
+#[test]
+fn test_link_configuration() {
+    if !can_run() {
+        return;
+    }
+    let mut topology = Topology::hub("link");
+    let server = topology.ns("server");
+
+    let link = output(&format!("-n {server} link show dev {TUN_NAME}"));
+    assert!(link.contains("mtu 1400"), "{link}");
+    let routes = output(&format!("-n {server} route show dev {TUN_NAME}"));
+    assert!(routes.contains("10.10.0.0/24 proto kernel"), "{routes}");
+    assert!(routes.contains("10.10.0.2 scope link"), "{routes}");
+    assert!(routes.contains("10.10.0.3 scope link"), "{routes}");
+
+    topology.stop("server");
+    let routes = output(&format!("-n {server} route show"));
+    assert!(!routes.contains(TUN_NAME), "{routes}");
+}
+
+#[test]
+fn test_attach_single_queue() {
+    if !can_run() {
+        return;
+    }
+    // wontun runs two threads, but the tuns of the clients have a single queue
+    let topology = Topology::hub_with(
+        "attach",
+        Hub {
+            attach: true,
+            ..Hub::default()
+        },
+    );
+    let a = topology.ns("client-A");
+    assert!(ping(&a, Ipv4Addr::new(10, 10, 0, 1), CONNECT_TIMEOUT));
+}
+
+#[test]
+fn test_full_tunnel() {
+    if !can_run() {
+        return;
+    }
+    let mut topology = Topology::hub_with(
+        "full",
+        Hub {
+            allowed_ips: "0.0.0.0/0",
+            ..Hub::default()
+        },
+    );
+    let a = topology.ns("client-A");
+    let src_valid_mark = format!("netns exec {a} sysctl -n net.ipv4.conf.all.src_valid_mark");
+
+    assert_eq!(output(&src_valid_mark).trim(), "1");
+    let rules = output(&format!("-n {a} rule show"));
+    assert!(
+        rules.contains("not from all fwmark 0x4e14 lookup 19988"),
+        "{rules}"
+    );
+    assert!(
+        rules.contains("lookup main suppress_prefixlength 0"),
+        "{rules}"
+    );
+    // the link of client B to the server, only reachable through the tunnel
+    assert!(ping(&a, Ipv4Addr::new(172, 18, 2, 1), CONNECT_TIMEOUT));
+
+    topology.stop("client-A");
+    let rules = output(&format!("-n {a} rule show"));
+    assert!(!rules.contains("19988"), "{rules}");
+    assert!(!rules.contains("suppress_prefixlength"), "{rules}");
+    assert_eq!(output(&src_valid_mark).trim(), "0");
+}
+
+#[test]
+fn test_kill_switch() {
+    if !can_run() {
+        return;
+    }
+    let mut topology = Topology::hub_with(
+        "kill",
+        Hub {
+            allowed_ips: "0.0.0.0/0",
+            // the server only learns the endpoints of the clients
+            args: &["--kill-switch"],
+            ..Hub::default()
+        },
+    );
+    let a = topology.ns("client-A");
+    // the server at the other end of the link of client A, next to the tunnel
+    let server_link = Ipv4Addr::new(172, 18, 3, 1);
+    let blocked = Duration::from_secs(1);
+
+    assert!(ping(&a, Ipv4Addr::new(10, 10, 0, 1), CONNECT_TIMEOUT));
+    assert!(!ping(&a, server_link, blocked));
+    // marked packets only reach the endpoints of the peers
+    assert!(send_marked(&a, SocketAddrV4::new(server_link, LISTEN_PORT)));
+    assert!(!send_marked(
+        &a,
+        SocketAddrV4::new(server_link, LISTEN_PORT + 1)
+    ));
+
+    // nothing leaks while the tunnel is down either
+    topology.stop("server");
+    assert!(!ping(&a, server_link, blocked));
+
+    topology.stop("client-A");
+    assert!(ping(&a, server_link, CONNECT_TIMEOUT));
+}
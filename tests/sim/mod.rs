@@ -0,0 +1,349 @@
+//! Devices in one process, connected by a `MemNetwork` and with a `MemTun` each, so that tests
+//! can inject IP packets at one node and see them come out of another without root or tun.
+//! Every node has a host thread standing in for the kernel behind the tun: packets for the
+//! node's own address are delivered to the test, others are routed back into the tunnel if
+//! the node forwards, like the hub of the sample confs. Nodes receive through an `Impaired`
+//! transport, unimpaired unless configured otherwise.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Index;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use wontun::{
+    Backend, Conf, Device, DeviceConfig, Impaired, Impairment, MemNetwork, MemTransport, MemTun,
+    Peer, PeerName, TunConfig,
+};
+
+/// Host threads check for shutdown at least this often
+const HOST_POLL: Duration = Duration::from_millis(10);
+
+/// Prefix of the payloads sent by `Sim::ping`
+const PING: &[u8] = b"ping";
+
+/// Protocol number of the packets built by `ipv4_packet`, UDP for lack of a better one
+const PROTO_TEST: u8 = 17;
+
+#[derive(Default)]
+pub struct Sim {
+    network: MemNetwork,
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone)]
+struct NodeConf {
+    name: String,
+    ip: Ipv4Addr,
+    forward: bool,
+    peers: Vec<PeerConf>,
+    impairment: Impairment,
+    seed: u64,
+}
+
+#[derive(Clone)]
+struct PeerConf {
+    name: String,
+    endpoint: Option<SocketAddrV4>,
+    allowed_ips: Vec<(Ipv4Addr, u8)>,
+}
+
+pub struct NodeBuilder<'a> {
+    sim: &'a mut Sim,
+    conf: NodeConf,
+    addr: SocketAddrV4,
+}
+
+pub struct Node {
+    conf: NodeConf,
+    addr: SocketAddrV4,
+    dev: Arc<Device<Impaired<MemTransport>, MemTun>>,
+    transport: Impaired<MemTransport>,
+    tun: MemTun,
+    delivered: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    host: Option<JoinHandle<()>>,
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A node with tunnel address `ip`, reachable by its peers at `addr`
+    pub fn node(&mut self, name: &str, ip: [u8; 4], addr: &str) -> NodeBuilder<'_> {
+        NodeBuilder {
+            sim: self,
+            conf: NodeConf {
+                name: name.to_string(),
+                ip: ip.into(),
+                forward: false,
+                peers: Vec::new(),
+                impairment: Impairment::default(),
+                seed: 0,
+            },
+            addr: addr.parse().expect("invalid node address"),
+        }
+    }
+
+    /// Shuts node `name` down and starts it again at `addr`, as if it restarted behind another
+    /// address. Its peers only learn the new one from its packets.
+    pub fn restart(&mut self, name: &str, addr: &str) {
+        let i = self.position(name);
+        let node = self.nodes.remove(i);
+        let conf = node.conf.clone();
+        drop(node);
+
+        let node = Node::start(&self.network, conf, addr.parse().expect("invalid address"));
+        self.nodes.insert(i, node);
+    }
+
+    /// Applies `peers`, `[Peer]` sections in ini, to node `name` as a conf reload would. A later
+    /// restart keeps them.
+    pub fn reload(&mut self, name: &str, peers: &str) {
+        let i = self.position(name);
+        let node = &mut self.nodes[i];
+        let ini = format!(
+            "[Interface]\nName={}\nAddress={}/24\nListenPort={}\n\n{peers}",
+            node.conf.name,
+            node.conf.ip,
+            node.addr.port()
+        );
+        let conf = Conf::parse_from(&ini).unwrap();
+        node.dev.reload(&conf).unwrap();
+        node.conf.peers = conf
+            .peers
+            .into_iter()
+            .map(|peer| PeerConf {
+                name: peer.name,
+                endpoint: peer.endpoint,
+                allowed_ips: peer.allowed_ips,
+            })
+            .collect();
+    }
+
+    /// Sends packets from `from` to the tunnel address of `to` until one arrives, e.g. while the
+    /// handshake is still under way. Returns whether one did before `timeout`.
+    pub fn ping(&self, from: &str, to: &str, timeout: Duration) -> bool {
+        let (from, to) = (&self[from], &self[to]);
+        let deadline = Instant::now() + timeout;
+        for seq in 0u32.. {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            let payload = [PING, &seq.to_be_bytes()].concat();
+            from.send(to.ip(), &payload);
+            let wait = Instant::now() + Duration::from_millis(50);
+            while let Ok(packet) = to
+                .delivered
+                .recv_timeout(wait.saturating_duration_since(Instant::now()))
+            {
+                if payload_of(&packet) == payload {
+                    return true;
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    fn position(&self, name: &str) -> usize {
+        self.nodes
+            .iter()
+            .position(|node| node.conf.name == name)
+            .unwrap_or_else(|| panic!("no node named {name}"))
+    }
+}
+
+impl Index<&str> for Sim {
+    type Output = Node;
+
+    fn index(&self, name: &str) -> &Node {
+        &self.nodes[self.position(name)]
+    }
+}
+
+impl NodeBuilder<'_> {
+    pub fn peer(
+        mut self,
+        name: &str,
+        endpoint: Option<&str>,
+        allowed_ips: &[([u8; 4], u8)],
+    ) -> Self {
+        self.conf.peers.push(PeerConf {
+            name: name.to_string(),
+            endpoint: endpoint.map(|addr| addr.parse().expect("invalid endpoint")),
+            allowed_ips: allowed_ips
+                .iter()
+                .map(|&(ip, cidr)| (ip.into(), cidr))
+                .collect(),
+        });
+        self
+    }
+
+    /// Routes packets for other addresses back into the tunnel
+    pub fn forward(mut self) -> Self {
+        self.conf.forward = true;
+        self
+    }
+
+    /// Impairs the datagrams the node sends and receives, drawn from `seed`
+    pub fn impair(mut self, impairment: Impairment, seed: u64) -> Self {
+        self.conf.impairment = impairment;
+        self.conf.seed = seed;
+        self
+    }
+
+    pub fn start(self) {
+        let node = Node::start(&self.sim.network, self.conf, self.addr);
+        self.sim.nodes.push(node);
+    }
+}
+
+impl Node {
+    fn start(network: &MemNetwork, conf: NodeConf, addr: SocketAddrV4) -> Self {
+        let tun = MemTun::new(&conf.name, 1).unwrap();
+        let transport = network.bind(addr).unwrap();
+        let transport = Impaired::new(transport, conf.impairment.clone(), conf.seed);
+        let mut dev = Device::with_interface(
+            DeviceConfig {
+                name: PeerName::new(&conf.name).unwrap(),
+                use_connected_peer: true,
+                listen_port: addr.port(),
+                tun: TunConfig::new(&conf.name),
+                fwmark: None,
+                backend: Backend::Epoll,
+            },
+            tun.clone(),
+            vec![transport.clone()],
+        )
+        .unwrap();
+        let mut peers = Vec::with_capacity(conf.peers.len());
+        for peer_conf in &conf.peers {
+            let mut peer = Peer::default();
+            if let Some(endpoint) = peer_conf.endpoint {
+                peer.set_endpoint(endpoint);
+            }
+            for &(ip, cidr) in &peer_conf.allowed_ips {
+                peer.add_allowed_ip(ip, cidr);
+            }
+            peers.push((PeerName::new(&peer_conf.name).unwrap(), peer));
+        }
+        dev.add_peers(peers);
+
+        let dev = Arc::new(dev);
+        dev.start().unwrap();
+        dev.spawn_event_loops(1).unwrap();
+
+        let (deliver, delivered) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let host = {
+            let (tun, stop, ip, forward) = (tun.clone(), Arc::clone(&stop), conf.ip, conf.forward);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let Some(packet) = tun.wait_sent(HOST_POLL) else {
+                        continue;
+                    };
+                    if destination_of(&packet) == Some(ip) {
+                        let _ = deliver.send(packet);
+                    } else if forward {
+                        let _ = tun.inject(&packet);
+                    }
+                }
+            })
+        };
+
+        Self {
+            conf,
+            addr,
+            dev,
+            transport,
+            tun,
+            delivered,
+            stop,
+            host: Some(host),
+        }
+    }
+
+    pub fn ip(&self) -> Ipv4Addr {
+        self.conf.ip
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// The node's transport, to change its impairment or rebind its view of the peers
+    pub fn transport(&self) -> &Impaired<MemTransport> {
+        &self.transport
+    }
+
+    /// Sends `payload` into the tunnel from the node's own address
+    pub fn send(&self, dst: Ipv4Addr, payload: &[u8]) {
+        self.inject(&ipv4_packet(self.ip(), dst, payload));
+    }
+
+    /// Sends a raw packet into the tunnel, e.g. one with a spoofed source
+    pub fn inject(&self, packet: &[u8]) {
+        self.tun.inject(packet).unwrap();
+    }
+
+    /// Next packet that arrived for the node's own address, other than late ones of `Sim::ping`
+    pub fn recv(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let packet = self.delivered.recv_timeout(timeout).ok()?;
+            if !payload_of(&packet).starts_with(PING) {
+                return Some(packet);
+            }
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(host) = self.host.take() {
+            let _ = host.join();
+        }
+        let _ = self.dev.shutdown();
+    }
+}
+
+/// A minimal IPv4 packet carrying `payload`
+pub fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let total_len = (20 + payload.len()) as u16;
+    let mut packet = vec![0u8; 20];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = PROTO_TEST;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+
+    let sum = packet
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub fn source_of(packet: &[u8]) -> Option<Ipv4Addr> {
+    let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    Some(src.into())
+}
+
+pub fn destination_of(packet: &[u8]) -> Option<Ipv4Addr> {
+    let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+    Some(dst.into())
+}
+
+pub fn payload_of(packet: &[u8]) -> &[u8] {
+    packet.get(20..).unwrap_or_default()
+}